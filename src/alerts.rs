@@ -0,0 +1,339 @@
+//! Alert rules, evaluated after each fetch of a host.
+
+use config::{AlertRule, Config, Metric};
+use data::{AlertData, HostData};
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use time;
+
+/// A rule violation.
+#[derive(Debug, Clone)]
+pub struct Alert {
+    pub rule: AlertRule,
+    pub host: String,
+    // What's wrong on the host: a disk, the memory, ...
+    pub subject: String,
+    pub value: f64,
+}
+
+impl Alert {
+    pub fn message(&self) -> String {
+        format!(
+            "[{}] {}: {} on `{}` is at {} ({} {}).",
+            self.rule.severity.unwrap_or_default().as_str(),
+            self.rule.name,
+            self.subject,
+            self.host,
+            format_value(self.rule.metric, self.value),
+            self.rule.operator.unwrap_or_default().symbol(),
+            format_value(self.rule.metric, self.rule.threshold),
+        )
+    }
+
+    pub fn resolved_message(&self) -> String {
+        format!(
+            "[resolved] {}: {} on `{}` is back to normal.",
+            self.rule.name,
+            self.subject,
+            self.host,
+        )
+    }
+}
+
+/// Something worth telling the humans about.
+#[derive(Debug, Clone)]
+pub enum Notification {
+    Firing(Alert),
+    Resolved(Alert),
+}
+
+impl Notification {
+    pub fn alert(&self) -> &Alert {
+        match *self {
+            Notification::Firing(ref alert) |
+            Notification::Resolved(ref alert) => alert,
+        }
+    }
+
+    pub fn status(&self) -> &'static str {
+        match *self {
+            Notification::Firing(_) => "firing",
+            Notification::Resolved(_) => "resolved",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match *self {
+            Notification::Firing(ref alert) => alert.message(),
+            Notification::Resolved(ref alert) => alert.resolved_message(),
+        }
+    }
+}
+
+fn format_value(metric: Metric, value: f64) -> String {
+    match metric {
+        Metric::MemoryRatio | Metric::DiskRatio => {
+            format!("{:.0}%", value * 100.0)
+        }
+        _ => format!("{}", value),
+    }
+}
+
+/// The rules used when none is configured: warn about full disks.
+pub fn default_rules() -> Vec<AlertRule> {
+    vec![
+        AlertRule {
+            name: "disk_full".to_string(),
+            metric: Metric::DiskRatio,
+            threshold: 0.98,
+            ..AlertRule::default()
+        },
+    ]
+}
+
+fn ratio(used: usize, total: usize) -> Option<f64> {
+    if total == 0 {
+        None
+    } else {
+        Some(used as f64 / total as f64)
+    }
+}
+
+/// Returns the (subject, value) pairs this rule looks at on this host.
+fn measure(rule: &AlertRule, host: &HostData) -> Vec<(String, f64)> {
+    let mut result = Vec::new();
+
+    match rule.metric {
+        Metric::Unreachable => {
+            let down = if host.state.is_ok() { 0.0 } else { 1.0 };
+            result.push(("host".to_string(), down));
+        }
+        Metric::LoadPerCore => {
+            if let (Some(uptime), Some(nproc)) = (host.uptime, host.nproc) {
+                if nproc > 0 {
+                    let load = uptime[0] as f64 / nproc as f64;
+                    result.push(("load".to_string(), load));
+                }
+            }
+        }
+        Metric::MemoryRatio => {
+            if let Some(ref memory) = host.memory {
+                if let Some(r) = ratio(memory.used, memory.total) {
+                    result.push(("memory".to_string(), r));
+                }
+            }
+        }
+        Metric::DiskRatio => for disk in &host.disks {
+            if let (Some(used), Some(size)) = (disk.used, disk.size) {
+                if let Some(r) = ratio(used, size) {
+                    let subject =
+                        format!("`{}` (`{}`)", disk.mountpoint, disk.name);
+                    result.push((subject, r));
+                }
+            }
+        },
+        Metric::SmartValue | Metric::SmartRaw => {
+            let attribute = match rule.attribute {
+                Some(ref attribute) => attribute,
+                None => return result,
+            };
+            for disk in &host.disks {
+                let attr = disk.attrs.as_ref().and_then(|a| a.get(attribute));
+                let attr = match attr {
+                    Some(attr) => attr,
+                    None => continue,
+                };
+                let value = if rule.metric == Metric::SmartValue {
+                    &attr.value
+                } else {
+                    &attr.raw
+                };
+                // Raw values sometimes have extra info after the number
+                let value = value
+                    .split_whitespace()
+                    .next()
+                    .and_then(|v| v.parse().ok());
+                if let Some(value) = value {
+                    let subject = format!("{} of `{}`", attribute, disk.name);
+                    result.push((subject, value));
+                }
+            }
+        }
+        Metric::Power => {
+            if let Some(ref power) = host.power {
+                result.push(("power".to_string(), power.current as f64));
+            }
+        }
+    }
+
+    result
+}
+
+/// Is this host concerned by this rule?
+fn in_scope(rule: &AlertRule, host: &HostData) -> bool {
+    let host_ok = rule.hosts
+        .as_ref()
+        .map_or(true, |hosts| hosts.contains(&host.name));
+    let location_ok = rule.locations.as_ref().map_or(true, |locations| {
+        host.location
+            .as_ref()
+            .map_or(false, |location| locations.contains(location))
+    });
+    host_ok && location_ok
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    // The condition holds, but not for long enough yet
+    Pending,
+    // The condition holds, and notifications are sent
+    Firing,
+}
+
+/// What we remember about a (host, rule, subject) between fetches.
+struct AlertState {
+    status: Status,
+    // Consecutive fetches where the condition held
+    count: u32,
+    // Time (rfc3339) when the alert started firing
+    since: String,
+    last_sent: Option<Instant>,
+    // Latest violation
+    alert: Alert,
+}
+
+type Key = (String, String, String);
+
+/// Keeps track of the alerts lifecycle between fetches.
+///
+/// An alert goes from pending to firing once the condition held for
+/// long enough, and is resolved when the condition doesn't hold anymore.
+pub struct AlertEngine {
+    states: HashMap<Key, AlertState>,
+}
+
+impl AlertEngine {
+    pub fn new() -> Self {
+        AlertEngine {
+            states: HashMap::new(),
+        }
+    }
+
+    /// Evaluates the rules against fresh data from a host.
+    ///
+    /// Returns the notifications that should be sent now.
+    pub fn evaluate(
+        &mut self,
+        rules: &[AlertRule],
+        host: &HostData,
+    ) -> Vec<Notification> {
+        let mut result = Vec::new();
+        let mut seen = HashSet::new();
+        let now = time::now().rfc3339().to_string();
+
+        for rule in rules.iter().filter(|rule| in_scope(rule, host)) {
+            let operator = rule.operator.unwrap_or_default();
+            let needed = rule.for_refreshes.unwrap_or(1);
+            let repeat =
+                Duration::from_secs(rule.repeat_interval.unwrap_or(30 * 60));
+
+            for (subject, value) in measure(rule, host) {
+                if !operator.compare(value, rule.threshold) {
+                    continue;
+                }
+
+                let alert = Alert {
+                    rule: rule.clone(),
+                    host: host.name.clone(),
+                    subject: subject.clone(),
+                    value: value,
+                };
+                let key = (host.name.clone(), rule.name.clone(), subject);
+                seen.insert(key.clone());
+                let state = self.states.entry(key).or_insert(AlertState {
+                    status: Status::Pending,
+                    count: 0,
+                    since: now.clone(),
+                    last_sent: None,
+                    alert: alert.clone(),
+                });
+                state.alert = alert;
+                state.count += 1;
+                if state.count < needed {
+                    continue;
+                }
+                if state.status == Status::Pending {
+                    state.status = Status::Firing;
+                    state.since = now.clone();
+                }
+                if let Some(last) = state.last_sent {
+                    if last.elapsed() < repeat {
+                        continue;
+                    }
+                }
+                state.last_sent = Some(Instant::now());
+                result.push(Notification::Firing(state.alert.clone()));
+            }
+        }
+
+        // Without data, we can't tell if the condition still holds.
+        let failed = !host.state.is_ok();
+
+        let keys: Vec<Key> = self.states
+            .keys()
+            .filter(|key| key.0 == host.name)
+            .cloned()
+            .collect();
+        for key in keys {
+            if seen.contains(&key) {
+                continue;
+            }
+            let unknown = failed &&
+                self.states[&key].alert.rule.metric != Metric::Unreachable;
+            if unknown {
+                continue;
+            }
+            let state = self.states.remove(&key).unwrap();
+            if state.status == Status::Firing {
+                result.push(Notification::Resolved(state.alert));
+            }
+        }
+
+        result
+    }
+
+    /// Forgets the alerts of hosts and rules that are not in the config
+    /// anymore, without notifying.
+    pub fn prune(&mut self, config: &Config, rules: &[AlertRule]) {
+        self.states.retain(|key, _| {
+            config.hosts.iter().any(|host| host.name == key.0) &&
+                rules.iter().any(|rule| rule.name == key.1)
+        });
+    }
+
+    /// Returns the currently firing alerts.
+    pub fn firing(&self) -> Vec<AlertData> {
+        let mut result: Vec<AlertData> = self.states
+            .values()
+            .filter(|state| state.status == Status::Firing)
+            .map(|state| AlertData {
+                rule: state.alert.rule.name.clone(),
+                severity: state
+                    .alert
+                    .rule
+                    .severity
+                    .unwrap_or_default()
+                    .as_str()
+                    .to_string(),
+                host: state.alert.host.clone(),
+                subject: state.alert.subject.clone(),
+                message: state.alert.message(),
+                since: state.since.clone(),
+            })
+            .collect();
+        result.sort_by(|a, b| a.since.cmp(&b.since));
+        result
+    }
+}
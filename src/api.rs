@@ -0,0 +1,640 @@
+//! JSON API to edit the config: hosts, locations and default authentication.
+
+use config::{AuthConfig, Config, HostConfig, LocationConfig, NotifierConfig};
+use errors;
+use http::{percent_decode, percent_encode};
+use server::Server;
+use validate::validate;
+
+use std::sync::Arc;
+
+use hyper::header::{ContentType, Location};
+use hyper::server::{Request, Response};
+use hyper::status::StatusCode;
+use reroute::RouterBuilder;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+
+/// Saves the config to disk.
+pub type ConfigSync = Arc<Fn(&Config) -> errors::Result<()> + Send + Sync>;
+
+/// Shown instead of passwords and tokens.
+///
+/// Sending it back in an update keeps the current value.
+pub const REDACTED: &str = "********";
+
+/// An error, sent as `{"error": message}`.
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+    // What `validate` found wrong with the updated config
+    problems: Vec<String>,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: String) -> Self {
+        ApiError {
+            status: status,
+            message: message,
+            problems: Vec::new(),
+        }
+    }
+
+    fn not_found(what: &str, name: &str) -> Self {
+        ApiError::new(
+            StatusCode::NotFound,
+            format!("{} `{}` not found", what, name),
+        )
+    }
+
+    fn conflict(message: String) -> Self {
+        ApiError::new(StatusCode::Conflict, message)
+    }
+
+    fn invalid(message: String) -> Self {
+        ApiError::new(StatusCode::UnprocessableEntity, message)
+    }
+}
+
+/// A list of named items in the config.
+trait Collection {
+    type Item: Serialize + DeserializeOwned + Clone;
+
+    // What an item is called in messages
+    const WHAT: &'static str;
+
+    fn items(config: &Config) -> &Vec<Self::Item>;
+    fn items_mut(config: &mut Config) -> &mut Vec<Self::Item>;
+    fn name(item: &Self::Item) -> &str;
+
+    /// Hides secrets before sending the item.
+    fn redact(_item: &mut Self::Item) {}
+
+    /// Puts back the secrets the client sent as `REDACTED`.
+    fn restore(_item: &mut Self::Item, _old: &Self::Item) {}
+
+    /// Updates references to an item that was renamed.
+    fn renamed(_config: &mut Config, _old: &str, _new: &str) {}
+
+    /// Fails if something still uses this item.
+    fn check_delete(_config: &Config, _name: &str) -> Result<(), ApiError> {
+        Ok(())
+    }
+}
+
+struct Hosts;
+
+struct Locations;
+
+fn redact_auth(auth: &mut AuthConfig) {
+    if auth.password.is_some() {
+        auth.password = Some(REDACTED.to_string());
+    }
+}
+
+fn restore_auth(auth: &mut AuthConfig, old: Option<&AuthConfig>) {
+    if auth.password.as_ref().map_or(false, |p| p == REDACTED) {
+        auth.password = old.and_then(|old| old.password.clone());
+    }
+}
+
+/// Does this comma-separated `proxy_jump` go through the host `name`?
+fn jumps_through(proxy_jump: &Option<String>, name: &str) -> bool {
+    proxy_jump.as_ref().map_or(false, |spec| {
+        spec.split(',').any(|hop| hop.trim() == name)
+    })
+}
+
+fn rename_hop(proxy_jump: &mut Option<String>, old: &str, new: &str) {
+    if let Some(ref mut spec) = *proxy_jump {
+        let hops: Vec<&str> = spec
+            .split(',')
+            .map(str::trim)
+            .map(|hop| if hop == old { new } else { hop })
+            .collect();
+        *spec = hops.join(",");
+    }
+}
+
+impl Collection for Hosts {
+    type Item = HostConfig;
+
+    const WHAT: &'static str = "host";
+
+    fn items(config: &Config) -> &Vec<HostConfig> {
+        &config.hosts
+    }
+
+    fn items_mut(config: &mut Config) -> &mut Vec<HostConfig> {
+        &mut config.hosts
+    }
+
+    fn name(item: &HostConfig) -> &str {
+        &item.name
+    }
+
+    fn redact(host: &mut HostConfig) {
+        if let Some(ref mut auth) = host.auth {
+            redact_auth(auth);
+        }
+        if host.token.is_some() {
+            host.token = Some(REDACTED.to_string());
+        }
+    }
+
+    fn restore(host: &mut HostConfig, old: &HostConfig) {
+        if let Some(ref mut auth) = host.auth {
+            restore_auth(auth, old.auth.as_ref());
+        }
+        if host.token.as_ref().map_or(false, |t| t == REDACTED) {
+            host.token = old.token.clone();
+        }
+    }
+
+    fn renamed(config: &mut Config, old: &str, new: &str) {
+        for host in &mut config.hosts {
+            rename_hop(&mut host.proxy_jump, old, new);
+        }
+        for location in &mut config.locations {
+            rename_hop(&mut location.proxy_jump, old, new);
+        }
+    }
+
+    fn check_delete(config: &Config, name: &str) -> Result<(), ApiError> {
+        let users = config
+            .hosts
+            .iter()
+            .filter(|h| jumps_through(&h.proxy_jump, name))
+            .map(|h| format!("host `{}`", h.name))
+            .chain(
+                config
+                    .locations
+                    .iter()
+                    .filter(|l| jumps_through(&l.proxy_jump, name))
+                    .map(|l| format!("location `{}`", l.name)),
+            )
+            .collect::<Vec<_>>();
+        if users.is_empty() {
+            return Ok(());
+        }
+        Err(ApiError::conflict(format!(
+            "host `{}` is a jump host for {}",
+            name,
+            users.join(", ")
+        )))
+    }
+}
+
+impl Collection for Locations {
+    type Item = LocationConfig;
+
+    const WHAT: &'static str = "location";
+
+    fn items(config: &Config) -> &Vec<LocationConfig> {
+        &config.locations
+    }
+
+    fn items_mut(config: &mut Config) -> &mut Vec<LocationConfig> {
+        &mut config.locations
+    }
+
+    fn name(item: &LocationConfig) -> &str {
+        &item.name
+    }
+
+    fn renamed(config: &mut Config, old: &str, new: &str) {
+        for host in &mut config.hosts {
+            if host.location.as_ref().map_or(false, |l| l == old) {
+                host.location = Some(new.to_string());
+            }
+        }
+    }
+
+    fn check_delete(config: &Config, name: &str) -> Result<(), ApiError> {
+        let hosts: Vec<String> = config
+            .hosts
+            .iter()
+            .filter(|h| h.location.as_ref().map_or(false, |l| l == name))
+            .map(|h| format!("`{}`", h.name))
+            .collect();
+        if hosts.is_empty() {
+            return Ok(());
+        }
+        Err(ApiError::conflict(format!(
+            "location `{}` is used by hosts {}",
+            name,
+            hosts.join(", ")
+        )))
+    }
+}
+
+/// Hides the secrets of the whole config.
+pub fn redact(config: &mut Config) {
+    for host in &mut config.hosts {
+        Hosts::redact(host);
+    }
+    if let Some(ref mut auth) = config.default {
+        redact_auth(auth);
+    }
+    // Slack hooks let anyone post to the channel. Webhook URLs and bodies,
+    // and command arguments, often hold tokens too.
+    if let Some(ref mut slack) = config.slack {
+        slack.hook = REDACTED.to_string();
+    }
+    for notifier in &mut config.notifiers {
+        match *notifier {
+            NotifierConfig::Slack { ref mut hook, .. } => {
+                *hook = REDACTED.to_string();
+            }
+            NotifierConfig::Webhook {
+                ref mut url,
+                ref mut body,
+                ..
+            } => {
+                *url = REDACTED.to_string();
+                if let Some(ref mut body) = *body {
+                    *body = REDACTED.to_string();
+                }
+            }
+            NotifierConfig::Command { ref mut args, .. } => {
+                for arg in args.iter_mut().flat_map(|args| args.iter_mut()) {
+                    *arg = REDACTED.to_string();
+                }
+            }
+            NotifierConfig::Email { .. } => (),
+        }
+    }
+    if let Some(auth) = config.http.as_mut().and_then(|h| h.auth.as_mut()) {
+        for user in &mut auth.users {
+            user.password_hash = REDACTED.to_string();
+        }
+        for token in &mut auth.tokens {
+            token.token = REDACTED.to_string();
+        }
+    }
+}
+
+/// Applies a JSON merge patch (RFC 7386): `null` removes a field.
+fn merge_patch(target: &mut Value, patch: Value) {
+    match patch {
+        Value::Object(patch) => {
+            if !target.is_object() {
+                *target = json!({});
+            }
+            let target = target.as_object_mut().unwrap();
+            for (key, value) in patch {
+                if value.is_null() {
+                    target.remove(&key);
+                } else {
+                    let field = target.entry(key).or_insert(Value::Null);
+                    merge_patch(field, value);
+                }
+            }
+        }
+        patch => *target = patch,
+    }
+}
+
+/// Reads the JSON body of the request.
+fn read_body(req: Request) -> Result<Value, ApiError> {
+    serde_json::from_reader(req).map_err(|e| {
+        ApiError::new(StatusCode::BadRequest, format!("Invalid JSON: {}", e))
+    })
+}
+
+/// Reads an item from JSON, failing if fields are missing or invalid.
+fn parse<T>(value: Value, what: &str) -> Result<T, ApiError>
+where
+    T: DeserializeOwned,
+{
+    serde_json::from_value(value)
+        .map_err(|e| ApiError::invalid(format!("Invalid {}: {}", what, e)))
+}
+
+fn show<C: Collection>(item: &C::Item) -> Value {
+    let mut item = item.clone();
+    C::redact(&mut item);
+    serde_json::to_value(&item).unwrap()
+}
+
+fn position<C: Collection>(
+    config: &Config,
+    name: &str,
+) -> Result<usize, ApiError> {
+    C::items(config)
+        .iter()
+        .position(|item| C::name(item) == name)
+        .ok_or_else(|| ApiError::not_found(C::WHAT, name))
+}
+
+/// Checks the updated config, and saves it.
+fn save(config: &Config, config_sync: &ConfigSync) -> Result<(), ApiError> {
+    let problems = validate(config, None);
+    if !problems.is_empty() {
+        let mut error = ApiError::invalid("Invalid config".to_string());
+        error.problems = problems.iter().map(|p| p.to_string()).collect();
+        return Err(error);
+    }
+    config_sync(config).map_err(|e| {
+        ApiError::new(
+            StatusCode::InternalServerError,
+            format!("Could not save config: {}", e),
+        )
+    })
+}
+
+fn list<C: Collection>(server: &Server) -> Value {
+    let config = server.current_conf();
+    Value::Array(C::items(&config).iter().map(show::<C>).collect())
+}
+
+fn get<C: Collection>(server: &Server, name: &str) -> Result<Value, ApiError> {
+    let config = server.current_conf();
+    let index = position::<C>(&config, name)?;
+    Ok(show::<C>(&C::items(&config)[index]))
+}
+
+/// Adds an item, and returns it with its name.
+fn create<C: Collection>(
+    server: &Server,
+    config_sync: &ConfigSync,
+    body: Value,
+) -> Result<(String, Value), ApiError> {
+    let item: C::Item = parse(body, C::WHAT)?;
+    let name = C::name(&item).to_string();
+    let result = show::<C>(&item);
+    server.with_conf(|config| {
+        if position::<C>(config, &name).is_ok() {
+            return Err(ApiError::conflict(
+                format!("{} `{}` already exists", C::WHAT, name),
+            ));
+        }
+        C::items_mut(config).push(item);
+        save(config, config_sync)
+    })?;
+    Ok((name, result))
+}
+
+/// Replaces an item with what `edit` makes of it, which may rename it.
+fn update<C, F>(
+    server: &Server,
+    config_sync: &ConfigSync,
+    name: &str,
+    edit: F,
+) -> Result<Value, ApiError>
+where
+    C: Collection,
+    F: FnOnce(&C::Item) -> Result<C::Item, ApiError>,
+{
+    let mut result = Value::Null;
+    server.with_conf(|config| {
+        let index = position::<C>(config, name)?;
+        let old = C::items(config)[index].clone();
+        let mut item = edit(&old)?;
+        C::restore(&mut item, &old);
+
+        let new_name = C::name(&item).to_string();
+        if new_name != name {
+            if position::<C>(config, &new_name).is_ok() {
+                return Err(ApiError::conflict(
+                    format!("{} `{}` already exists", C::WHAT, new_name),
+                ));
+            }
+            C::renamed(config, name, &new_name);
+        }
+        result = show::<C>(&item);
+        C::items_mut(config)[index] = item;
+        save(config, config_sync)
+    })?;
+    Ok(result)
+}
+
+fn delete<C: Collection>(
+    server: &Server,
+    config_sync: &ConfigSync,
+    name: &str,
+) -> Result<(), ApiError> {
+    server.with_conf(|config| {
+        let index = position::<C>(config, name)?;
+        C::check_delete(config, name)?;
+        C::items_mut(config).remove(index);
+        save(config, config_sync)
+    })
+}
+
+/// Replaces the default authentication.
+///
+/// Returns whether there was none before.
+fn put_default(
+    server: &Server,
+    config_sync: &ConfigSync,
+    body: Value,
+) -> Result<(bool, Value), ApiError> {
+    let mut auth: AuthConfig = parse(body, "authentication")?;
+    let mut created = false;
+    server.with_conf(|config| {
+        restore_auth(&mut auth, config.default.as_ref());
+        created = config.default.is_none();
+        config.default = Some(auth.clone());
+        save(config, config_sync)
+    })?;
+    redact_auth(&mut auth);
+    Ok((created, serde_json::to_value(&auth).unwrap()))
+}
+
+fn patch_default(
+    server: &Server,
+    config_sync: &ConfigSync,
+    patch: Value,
+) -> Result<Value, ApiError> {
+    let mut result = Value::Null;
+    server.with_conf(|config| {
+        let mut value = match config.default {
+            Some(ref auth) => serde_json::to_value(auth).unwrap(),
+            None => {
+                return Err(ApiError::new(
+                    StatusCode::NotFound,
+                    "No default authentication".to_string(),
+                ))
+            }
+        };
+        merge_patch(&mut value, patch);
+        let mut auth: AuthConfig = parse(value, "authentication")?;
+        restore_auth(&mut auth, config.default.as_ref());
+        config.default = Some(auth.clone());
+        redact_auth(&mut auth);
+        result = serde_json::to_value(&auth).unwrap();
+        save(config, config_sync)
+    })?;
+    Ok(result)
+}
+
+fn send_json(mut resp: Response, status: StatusCode, body: &Value) {
+    *resp.status_mut() = status;
+    resp.headers_mut().set(ContentType::json());
+    resp.send(&serde_json::to_vec(body).unwrap()).ok();
+}
+
+fn send_api_error(resp: Response, error: ApiError) {
+    let mut body = json!({ "error": error.message });
+    if !error.problems.is_empty() {
+        body["problems"] = json!(error.problems);
+    }
+    send_json(resp, error.status, &body);
+}
+
+/// Sends the value with `200 OK`, or the error.
+fn reply(resp: Response, result: Result<Value, ApiError>) {
+    match result {
+        Ok(body) => send_json(resp, StatusCode::Ok, &body),
+        Err(e) => send_api_error(resp, e),
+    }
+}
+
+/// Sends `204 No Content`, or the error.
+fn reply_empty(mut resp: Response, result: Result<(), ApiError>) {
+    match result {
+        Ok(()) => {
+            *resp.status_mut() = StatusCode::NoContent;
+            resp.send(b"").ok();
+        }
+        Err(e) => send_api_error(resp, e),
+    }
+}
+
+/// Adds the routes of a collection, under `/api/<path>`.
+fn register_collection<C: Collection + 'static>(
+    builder: &mut RouterBuilder,
+    path: &str,
+    server: &Arc<Server>,
+    config_sync: &ConfigSync,
+) {
+    let all = format!("^/api/{}$", path);
+    let one = format!("^/api/{}/([^/?]+)$", path);
+
+    let server_ = server.clone();
+    builder.get(&all, move |_, resp, _| {
+        reply(resp, Ok(list::<C>(&server_)));
+    });
+
+    let server_ = server.clone();
+    let config_sync_ = config_sync.clone();
+    let prefix = format!("/api/{}/", path);
+    builder.post(&all, move |req, mut resp, _| {
+        let result = read_body(req)
+            .and_then(|body| create::<C>(&server_, &config_sync_, body));
+        match result {
+            Ok((name, item)) => {
+                let location = format!("{}{}", prefix, percent_encode(&name));
+                resp.headers_mut().set(Location(location));
+                send_json(resp, StatusCode::Created, &item);
+            }
+            Err(e) => send_api_error(resp, e),
+        }
+    });
+
+    let server_ = server.clone();
+    builder.get(&one, move |_, resp, captures| {
+        let name = percent_decode(&captures.unwrap()[1]);
+        reply(resp, get::<C>(&server_, &name));
+    });
+
+    let server_ = server.clone();
+    let config_sync_ = config_sync.clone();
+    builder.put(&one, move |req, resp, captures| {
+        let name = percent_decode(&captures.unwrap()[1]);
+        let result = read_body(req).and_then(|body| {
+            update::<C, _>(&server_, &config_sync_, &name, |_| {
+                parse(body, C::WHAT)
+            })
+        });
+        reply(resp, result);
+    });
+
+    let server_ = server.clone();
+    let config_sync_ = config_sync.clone();
+    builder.patch(&one, move |req, resp, captures| {
+        let name = percent_decode(&captures.unwrap()[1]);
+        let result = read_body(req).and_then(|patch| {
+            update::<C, _>(&server_, &config_sync_, &name, |old| {
+                let mut value = serde_json::to_value(old).unwrap();
+                merge_patch(&mut value, patch);
+                parse(value, C::WHAT)
+            })
+        });
+        reply(resp, result);
+    });
+
+    let server_ = server.clone();
+    let config_sync_ = config_sync.clone();
+    builder.delete(&one, move |_, resp, captures| {
+        let name = percent_decode(&captures.unwrap()[1]);
+        reply_empty(resp, delete::<C>(&server_, &config_sync_, &name));
+    });
+}
+
+/// Adds the routes of the API.
+pub fn register(
+    builder: &mut RouterBuilder,
+    server: &Arc<Server>,
+    config_sync: &ConfigSync,
+) {
+    register_collection::<Hosts>(builder, "hosts", server, config_sync);
+    register_collection::<Locations>(
+        builder,
+        "locations",
+        server,
+        config_sync,
+    );
+
+    let server_ = server.clone();
+    builder.get("^/api/default$", move |_, resp, _| {
+        let result = match server_.current_conf().default.clone() {
+            Some(mut auth) => {
+                redact_auth(&mut auth);
+                Ok(serde_json::to_value(&auth).unwrap())
+            }
+            None => Err(ApiError::new(
+                StatusCode::NotFound,
+                "No default authentication".to_string(),
+            )),
+        };
+        reply(resp, result);
+    });
+
+    let server_ = server.clone();
+    let config_sync_ = config_sync.clone();
+    builder.put("^/api/default$", move |req, resp, _| {
+        let result = read_body(req)
+            .and_then(|body| put_default(&server_, &config_sync_, body));
+        match result {
+            Ok((true, auth)) => send_json(resp, StatusCode::Created, &auth),
+            Ok((false, auth)) => send_json(resp, StatusCode::Ok, &auth),
+            Err(e) => send_api_error(resp, e),
+        }
+    });
+
+    let server_ = server.clone();
+    let config_sync_ = config_sync.clone();
+    builder.patch("^/api/default$", move |req, resp, _| {
+        let result = read_body(req)
+            .and_then(|patch| patch_default(&server_, &config_sync_, patch));
+        reply(resp, result);
+    });
+
+    let server_ = server.clone();
+    let config_sync_ = config_sync.clone();
+    builder.delete("^/api/default$", move |_, resp, _| {
+        let result = server_.with_conf(|config| {
+            if config.default.take().is_none() {
+                return Err(ApiError::new(
+                    StatusCode::NotFound,
+                    "No default authentication".to_string(),
+                ));
+            }
+            save(config, &config_sync_)
+        });
+        reply_empty(resp, result);
+    });
+}
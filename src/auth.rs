@@ -0,0 +1,70 @@
+//! Who may use the web interface, from the `[http.auth]` section.
+
+use config::{HttpAuthConfig, Role};
+use push::token_matches;
+
+use hyper::header::{Authorization, Basic, Bearer, Headers};
+use hyper::method::Method;
+
+use argon2;
+use bcrypt;
+
+/// Is this password the one hashed in `hash`?
+///
+/// Unknown hash formats never match.
+fn password_matches(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        argon2::verify_encoded(hash, password.as_bytes()).unwrap_or(false)
+    } else if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else {
+        false
+    }
+}
+
+/// Does farmview know how to check this hash?
+pub fn known_hash(hash: &str) -> bool {
+    hash.starts_with("$argon2") || hash.starts_with("$2")
+}
+
+/// Returns the role of whoever sent these headers, if they are known.
+pub fn authenticate(
+    auth: &HttpAuthConfig,
+    headers: &Headers,
+) -> Option<Role> {
+    if let Some(basic) = headers.get::<Authorization<Basic>>() {
+        let password = basic.password.as_ref().map_or("", String::as_str);
+        return auth.users
+            .iter()
+            .find(|user| user.name == basic.username)
+            .filter(|user| password_matches(password, &user.password_hash))
+            .map(|user| user.role);
+    }
+    if let Some(bearer) = headers.get::<Authorization<Bearer>>() {
+        // Check them all, so the time doesn't tell which one matched.
+        return auth.tokens
+            .iter()
+            .filter(|token| token_matches(&token.token, &bearer.token))
+            .map(|token| token.role)
+            .fold(None, |best, role| best.max(Some(role)));
+    }
+    None
+}
+
+/// Role needed for a request, or `None` if it checks credentials itself.
+pub fn required_role(method: &Method, path: &str) -> Option<Role> {
+    let path = path.split('?').next().unwrap_or("");
+    // Hosts authenticate their reports with their own token.
+    if *method == Method::Post && path.starts_with("/report/") {
+        return None;
+    }
+
+    let reading = *method == Method::Get || *method == Method::Head;
+    // The config shows everything about the hosts, even redacted.
+    let config = path == "/config" || path.starts_with("/api/");
+    if reading && !config {
+        Some(Role::Viewer)
+    } else {
+        Some(Role::Admin)
+    }
+}
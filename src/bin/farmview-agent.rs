@@ -0,0 +1,38 @@
+//! Collection agent, uploaded to hosts instead of `fetch.py`.
+//!
+//! Prints the `HostData` for the current machine as JSON on stdout.
+
+#[macro_use]
+extern crate serde_derive;
+
+extern crate libc;
+extern crate serde_json;
+
+// Share the types with farmview itself, so the schema cannot drift.
+#[allow(dead_code)]
+#[path = "../data.rs"]
+mod data;
+#[path = "../collect.rs"]
+mod collect;
+
+use std::env;
+use std::io;
+use std::process;
+
+fn main() {
+    // Expects one argument: the network interface name
+    let iface = match env::args().nth(1) {
+        Some(iface) => iface,
+        None => {
+            eprintln!("Usage: farmview-agent IFACE");
+            process::exit(1);
+        }
+    };
+
+    let data = collect::collect(&iface);
+    if let Err(e) = serde_json::to_writer(io::stdout(), &data) {
+        eprintln!("Could not write data: {}", e);
+        process::exit(1);
+    }
+    println!();
+}
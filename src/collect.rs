@@ -0,0 +1,394 @@
+//! Native data collection, reading /proc, /sys and statvfs directly.
+//!
+//! SMART attributes and power come from `smartctl` and `ipmitool`, through
+//! `sudo -n`, like `fetch.py` does; they are left out when that fails.
+//!
+//! This is shared between the `farmview-agent` binary and farmview itself,
+//! so it must only depend on `data`.
+
+use data::{
+    Attribute, DiskData, HostData, MemoryData, NetworkData, PowerData,
+};
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io::Read;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::ptr;
+use std::thread;
+use std::time::Duration;
+
+use libc;
+
+/// Seconds during which network traffic is measured.
+const NETWORK_SAMPLE: u64 = 1;
+
+/// Collects data about the current machine.
+///
+/// `iface` is the network interface to monitor.
+pub fn collect(iface: &str) -> HostData {
+    HostData {
+        hostname: get_hostname(),
+        nproc: get_nproc(),
+        uptime: get_uptime(),
+        memory: get_memory_info(),
+        disks: get_disks(),
+        power: get_power(),
+        network: Some(get_network(iface)),
+        ..HostData::default()
+    }
+}
+
+fn read_file<P: AsRef<Path>>(path: P) -> Option<String> {
+    let mut buffer = String::new();
+    fs::File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut buffer))
+        .ok()
+        .map(|_| buffer)
+}
+
+/// Runs a command through `sudo -n`, returning its output if it succeeds.
+///
+/// `-n` fails instead of prompting for a password.
+fn run_sudo(args: &[&str]) -> Option<String> {
+    let output = Command::new("sudo")
+        .arg("-n")
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if output.status.success() {
+        String::from_utf8(output.stdout).ok()
+    } else {
+        None
+    }
+}
+
+fn get_hostname() -> Option<String> {
+    read_file("/proc/sys/kernel/hostname").map(|h| h.trim().to_string())
+}
+
+fn get_nproc() -> Option<u8> {
+    let n = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if n > 0 {
+        Some(n as u8)
+    } else {
+        None
+    }
+}
+
+fn get_uptime() -> Option<[f32; 3]> {
+    let loadavg = read_file("/proc/loadavg")?;
+    let loads: Vec<f32> = loadavg
+        .split_whitespace()
+        .take(3)
+        .filter_map(|token| token.parse().ok())
+        .collect();
+    if loads.len() == 3 {
+        Some([loads[0], loads[1], loads[2]])
+    } else {
+        None
+    }
+}
+
+fn get_memory_info() -> Option<MemoryData> {
+    let meminfo = read_file("/proc/meminfo")?;
+    // Values are in kiB
+    let values: HashMap<&str, usize> = meminfo
+        .lines()
+        .filter_map(|line| {
+            let mut tokens = line.split_whitespace();
+            let key = tokens.next()?.trim_right_matches(':');
+            let value = tokens.next()?.parse().ok()?;
+            Some((key, value))
+        })
+        .collect();
+
+    let total = *values.get("MemTotal")?;
+    let available = match values.get("MemAvailable") {
+        Some(&available) => available,
+        None => values.get("MemFree")? + values.get("Cached")?,
+    };
+
+    Some(MemoryData {
+        total: total * 1024,
+        used: total.saturating_sub(available) * 1024,
+    })
+}
+
+/// Decodes the octal escapes (`\040`) used in /proc/mounts.
+fn unescape_mount(path: &str) -> String {
+    let mut result = String::new();
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        let code: String = chars.by_ref().take(3).collect();
+        match u8::from_str_radix(&code, 8) {
+            Ok(byte) => result.push(byte as char),
+            Err(_) => {
+                result.push(c);
+                result.push_str(&code);
+            }
+        }
+    }
+    result
+}
+
+/// Returns the mountpoint of each mounted block device.
+fn get_mounts() -> HashMap<String, String> {
+    let mut result = HashMap::new();
+    let mounts = match read_file("/proc/mounts") {
+        Some(mounts) => mounts,
+        None => return result,
+    };
+
+    for line in mounts.lines() {
+        let mut tokens = line.split_whitespace();
+        let (source, mountpoint) = match (tokens.next(), tokens.next()) {
+            (Some(source), Some(mountpoint)) => (source, mountpoint),
+            _ => continue,
+        };
+        if !source.starts_with("/dev/") {
+            continue;
+        }
+        // Resolve things like /dev/disk/by-uuid/...
+        let device = fs::canonicalize(source)
+            .ok()
+            .and_then(|p| p.to_str().map(String::from))
+            .unwrap_or_else(|| source.to_string());
+        // Only keep the first mountpoint for each device.
+        result
+            .entry(device)
+            .or_insert_with(|| unescape_mount(mountpoint));
+    }
+    result
+}
+
+/// Returns (size, available, used), in bytes.
+fn statvfs(path: &str) -> Option<(usize, usize, usize)> {
+    let path = CString::new(path).ok()?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    let block = stat.f_frsize as usize;
+    let size = stat.f_blocks as usize * block;
+    let available = stat.f_bavail as usize * block;
+    let used = (stat.f_blocks - stat.f_bfree) as usize * block;
+    Some((size, available, used))
+}
+
+fn get_model(device: &Path) -> Option<String> {
+    let model = read_file(device.join("device/model"))?;
+    let model = model.trim();
+    match read_file(device.join("device/vendor")) {
+        // ATA disks all report the same vendor, skip it.
+        Some(ref vendor) if vendor.trim() != "ATA" => {
+            Some(format!("{} {}", vendor.trim(), model))
+        }
+        _ => Some(model.to_string()),
+    }
+}
+
+/// Parses the attribute table printed by `smartctl -A`.
+fn parse_smart_attrs(output: &str) -> Option<HashMap<String, Attribute>> {
+    let mut lines = output.lines();
+    // Skip everything up to the header of the table.
+    lines.by_ref().find(|line| line.trim_left().starts_with("ID#"))?;
+
+    let attrs: HashMap<_, _> = lines
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .take_while(|tokens| tokens.len() >= 10)
+        .map(|tokens| {
+            let attr = Attribute {
+                value: tokens[3].to_string(),
+                raw: tokens[9..].join(" "),
+            };
+            (tokens[1].to_string(), attr)
+        })
+        .collect();
+    if attrs.is_empty() {
+        None
+    } else {
+        Some(attrs)
+    }
+}
+
+fn get_attrs(device: &str) -> Option<HashMap<String, Attribute>> {
+    parse_smart_attrs(&run_sudo(&["smartctl", "-A", device])?)
+}
+
+/// Parses `ipmitool sensor reading`, like `Current 1 | 0.60`.
+fn parse_power(output: &str) -> Option<PowerData> {
+    let current = output.split('|').nth(1)?.trim().parse().ok()?;
+    Some(PowerData { current })
+}
+
+fn get_power() -> Option<PowerData> {
+    parse_power(&run_sudo(&["ipmitool", "sensor", "reading", "Current 1"])?)
+}
+
+fn get_disks() -> Vec<DiskData> {
+    let mounts = get_mounts();
+    let mut result = Vec::new();
+
+    let devices = match fs::read_dir("/sys/block") {
+        Ok(devices) => devices,
+        Err(_) => return result,
+    };
+
+    for device in devices.filter_map(|d| d.ok()) {
+        let path = device.path();
+        let name = device.file_name().to_string_lossy().into_owned();
+        let model = get_model(&path);
+
+        // The device itself, and each of its partitions.
+        let mut candidates = vec![name.clone()];
+        if let Ok(children) = fs::read_dir(&path) {
+            candidates.extend(
+                children
+                    .filter_map(|c| c.ok())
+                    .filter(|c| c.path().join("partition").exists())
+                    .map(|c| c.file_name().to_string_lossy().into_owned()),
+            );
+        }
+
+        // SMART attributes belong to the whole device, and are only read
+        // if something on it is mounted.
+        let mut attrs = None;
+        let mut attrs_read = false;
+
+        // Only mounted devices are reported.
+        for candidate in candidates {
+            let dev = format!("/dev/{}", candidate);
+            let mountpoint = match mounts.get(&dev) {
+                Some(mountpoint) => mountpoint,
+                None => continue,
+            };
+            let (size, available, used) = match statvfs(mountpoint) {
+                Some(stats) => stats,
+                None => continue,
+            };
+            if !attrs_read {
+                attrs = get_attrs(&format!("/dev/{}", name));
+                attrs_read = true;
+            }
+            result.push(DiskData {
+                size: Some(size),
+                available: Some(available),
+                used: Some(used),
+                mountpoint: mountpoint.clone(),
+                name: dev,
+                model: model.clone(),
+                attrs: attrs.clone(),
+            });
+        }
+    }
+
+    result.sort_by(|a, b| a.mountpoint.cmp(&b.mountpoint));
+    result
+}
+
+fn get_ip(iface: &str) -> Option<String> {
+    let mut result = None;
+    unsafe {
+        let mut addrs: *mut libc::ifaddrs = ptr::null_mut();
+        if libc::getifaddrs(&mut addrs) != 0 {
+            return None;
+        }
+
+        let mut current = addrs;
+        while !current.is_null() {
+            let addr = &*current;
+            current = addr.ifa_next;
+
+            if addr.ifa_addr.is_null() ||
+                (*addr.ifa_addr).sa_family as i32 != libc::AF_INET
+            {
+                continue;
+            }
+            if CStr::from_ptr(addr.ifa_name).to_string_lossy() != iface {
+                continue;
+            }
+
+            let sin = &*(addr.ifa_addr as *const libc::sockaddr_in);
+            let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+            result = Some(ip.to_string());
+            break;
+        }
+
+        libc::freeifaddrs(addrs);
+    }
+    result
+}
+
+/// Returns the total (rx, tx) bytes for this interface.
+fn read_traffic(iface: &str) -> Option<(usize, usize)> {
+    let stats = Path::new("/sys/class/net").join(iface).join("statistics");
+    let read = |name| {
+        read_file(stats.join(name)).and_then(|v| v.trim().parse().ok())
+    };
+    Some((read("rx_bytes")?, read("tx_bytes")?))
+}
+
+fn get_network(iface: &str) -> NetworkData {
+    let mut result = NetworkData {
+        ip: get_ip(iface),
+        ..NetworkData::default()
+    };
+
+    if let Some((rx0, tx0)) = read_traffic(iface) {
+        thread::sleep(Duration::from_secs(NETWORK_SAMPLE));
+        if let Some((rx1, tx1)) = read_traffic(iface) {
+            let delay = NETWORK_SAMPLE as usize;
+            result.rx = Some(rx1.saturating_sub(rx0) / delay);
+            result.tx = Some(tx1.saturating_sub(tx0) / delay);
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smartctl_attributes() {
+        let output = "\
+smartctl 7.1 2019-12-30 r5022 [x86_64-linux-5.4.0] (local build)
+
+=== START OF READ SMART DATA SECTION ===
+SMART Attributes Data Structure revision number: 16
+Vendor Specific SMART Attributes with Thresholds:
+ID# ATTRIBUTE_NAME          FLAG     VALUE WORST THRESH TYPE      \
+UPDATED  WHEN_FAILED RAW_VALUE
+  5 Reallocated_Sector_Ct   0x0033   100   100   010    Pre-fail  \
+Always       -       0
+194 Temperature_Celsius     0x0022   064   052   000    Old_age   \
+Always       -       36 (Min/Max 19/48)
+
+";
+        let attrs = parse_smart_attrs(output).unwrap();
+        assert_eq!(attrs.len(), 2);
+        assert_eq!(attrs["Reallocated_Sector_Ct"].value, "100");
+        assert_eq!(attrs["Temperature_Celsius"].raw, "36 (Min/Max 19/48)");
+
+        assert!(parse_smart_attrs("Unable to detect device type").is_none());
+    }
+
+    #[test]
+    fn ipmitool_current() {
+        let power = parse_power("Current 1        | 0.60\n").unwrap();
+        assert_eq!(power.current, 0.6);
+        assert!(parse_power("Current 1        | na\n").is_none());
+    }
+}
@@ -1,12 +1,18 @@
+use std::env;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use toml;
-use std::io::{Read, Write};
+use xdg;
+use toml_edit::{ArrayOfTables, Document, Item, Table, Value};
+use ssh_config;
+use validate::validate;
+use std::io::{self, Read, Write};
 use errors::*;
 
 // Serialization made with serde
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct Config {
     #[serde(skip_serializing_if="Option::is_none")]
     pub http: Option<HttpConfig>,
@@ -19,29 +25,408 @@ pub struct Config {
 
     pub slack: Option<SlackConfig>,
 
-    // Seconds between two refresh rates
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub ssh: Option<SshConfig>,
+
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub history: Option<HistoryConfig>,
+
+    // If empty, only warn about full disks.
+    #[serde(default, skip_serializing_if="Vec::is_empty")]
+    pub alerts: Vec<AlertRule>,
+
+    #[serde(default, skip_serializing_if="Vec::is_empty")]
+    pub notifiers: Vec<NotifierConfig>,
+
+    // Seconds between two fetches of a host. Defaults to 30.
     #[serde(skip_serializing_if="Option::is_none")]
     pub refresh_delay: Option<u64>,
+
+    // Failing hosts are retried less and less often, up to this many
+    // seconds between two attempts. Defaults to 600.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub max_backoff: Option<u64>,
+
+    // Seconds without a report before a pushing host is stale.
+    // Defaults to 300.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub stale_after: Option<u64>,
+
+    // Local path to the `farmview-agent` binary uploaded to hosts.
+    // Defaults to the one next to the farmview executable.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub agent_path: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct SlackConfig {
     pub channel: String,
     pub hook: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct SshConfig {
+    // Defaults to farmview_known_hosts, next to the default config file.
+    // New keys are only ever appended to it.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub known_hosts: Option<String>,
+    // Defaults to `accept_new`
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub host_key_policy: Option<HostKeyPolicy>,
+    // OpenSSH client config used to resolve host addresses.
+    // Defaults to ~/.ssh/config, set to "" to disable.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub config_file: Option<String>,
+
+    // Defaults for all hosts, which can override them.
+    // Defaults to 22
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub port: Option<u16>,
+    // Seconds, defaults to 15
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub connect_timeout: Option<u64>,
+    // Seconds, defaults to 15
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub read_timeout: Option<u64>,
+    // Where the collector is uploaded. Defaults to the login directory.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub remote_dir: Option<String>,
+    // Prepended to the collector command, like `python3` or `sudo -n`
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub command_prefix: Option<String>,
+}
+
+impl SshConfig {
+    pub fn known_hosts_path(&self) -> PathBuf {
+        match self.known_hosts {
+            Some(ref path) => expand_home(path),
+            None => xdg::BaseDirectories::new()
+                .map(|dirs| dirs.get_config_home())
+                .unwrap_or_else(|_| expand_home("~/.config"))
+                .join("farmview_known_hosts"),
+        }
+    }
+
+    pub fn config_file_path(&self) -> Option<PathBuf> {
+        match self.config_file {
+            Some(ref path) if path.is_empty() => None,
+            Some(ref path) => Some(expand_home(path)),
+            None => Some(expand_home("~/.ssh/config")),
+        }
+    }
+}
+
+/// Replaces a leading `~` with the home directory.
+pub fn expand_home(path: &str) -> PathBuf {
+    if path == "~" || path.starts_with("~/") {
+        if let Some(home) = env::home_dir() {
+            return home.join(path[1..].trim_left_matches('/'));
+        }
+    }
+    PathBuf::from(path)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HostKeyPolicy {
+    // Only connect to hosts already in known_hosts
+    Strict,
+    // Add unknown hosts to known_hosts, refuse changed keys
+    AcceptNew,
+    // Don't check anything
+    Off,
+}
+
+impl Default for HostKeyPolicy {
+    fn default() -> Self {
+        HostKeyPolicy::AcceptNew
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct HistoryConfig {
+    // Directory where the history is stored
+    pub path: String,
+
+    // Days after which data is deleted. Defaults to 30.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub retention_days: Option<u64>,
+
+    // Minimum seconds between two records for a host.
+    // Defaults to recording every refresh.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub resolution: Option<u64>,
+
+    // Days after which data is downsampled. Never by default.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub downsample_after_days: Option<u64>,
+
+    // Seconds between two records once downsampled. Defaults to 3600.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub downsample_resolution: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct AlertRule {
+    pub name: String,
+    pub metric: Metric,
+    // For SMART metrics, the name of the attribute
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub attribute: Option<String>,
+
+    // Defaults to `>`
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub operator: Option<Operator>,
+    #[serde(default)]
+    pub threshold: f64,
+
+    // Number of consecutive refreshes before firing. Defaults to 1.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub for_refreshes: Option<u32>,
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub severity: Option<Severity>,
+
+    // Only apply to these hosts (by name)
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub hosts: Option<Vec<String>>,
+    // Only apply to hosts in these locations
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub locations: Option<Vec<String>>,
+
+    // Seconds before sending the same alert again. Defaults to 30 minutes.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub repeat_interval: Option<u64>,
+
+    // Names of the notifiers to use. Defaults to all of them.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub notify: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    // 1-minute load average divided by the number of processors
+    LoadPerCore,
+    // Used memory over total memory
+    MemoryRatio,
+    // Used space over size, for each disk
+    DiskRatio,
+    // Normalized value of a SMART attribute, for each disk
+    SmartValue,
+    // Raw value of a SMART attribute, for each disk
+    SmartRaw,
+    // Current drawn, in Amps
+    Power,
+    // 1 if the host could not be fetched, 0 otherwise
+    Unreachable,
+}
+
+impl Default for Metric {
+    fn default() -> Self {
+        Metric::Unreachable
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    #[serde(rename = ">")]
+    Greater,
+    #[serde(rename = ">=")]
+    GreaterOrEqual,
+    #[serde(rename = "<")]
+    Less,
+    #[serde(rename = "<=")]
+    LessOrEqual,
+    #[serde(rename = "==")]
+    Equal,
+    #[serde(rename = "!=")]
+    NotEqual,
+}
+
+impl Default for Operator {
+    fn default() -> Self {
+        Operator::Greater
+    }
+}
+
+impl Operator {
+    pub fn symbol(&self) -> &'static str {
+        match *self {
+            Operator::Greater => ">",
+            Operator::GreaterOrEqual => ">=",
+            Operator::Less => "<",
+            Operator::LessOrEqual => "<=",
+            Operator::Equal => "==",
+            Operator::NotEqual => "!=",
+        }
+    }
+
+    pub fn compare(&self, value: f64, threshold: f64) -> bool {
+        match *self {
+            Operator::Greater => value > threshold,
+            Operator::GreaterOrEqual => value >= threshold,
+            Operator::Less => value < threshold,
+            Operator::LessOrEqual => value <= threshold,
+            Operator::Equal => value == threshold,
+            Operator::NotEqual => value != threshold,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Default for Severity {
+    fn default() -> Self {
+        Severity::Warning
+    }
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NotifierConfig {
+    Slack {
+        name: String,
+        channel: String,
+        hook: String,
+    },
+    // POSTs a JSON body to an URL
+    Webhook {
+        name: String,
+        url: String,
+        // Template, where `{{ host }}`, `{{ message }}`, ... are replaced.
+        // Defaults to a JSON object with all variables.
+        #[serde(skip_serializing_if="Option::is_none")]
+        body: Option<String>,
+    },
+    // Sends a mail through an SMTP relay
+    Email {
+        name: String,
+        // host:port
+        server: String,
+        from: String,
+        to: Vec<String>,
+    },
+    // Runs a local command, with FARMVIEW_* environment variables
+    Command {
+        name: String,
+        command: String,
+        #[serde(skip_serializing_if="Option::is_none")]
+        args: Option<Vec<String>>,
+    },
+}
+
+impl NotifierConfig {
+    pub fn name(&self) -> &str {
+        match *self {
+            NotifierConfig::Slack { ref name, .. } |
+            NotifierConfig::Webhook { ref name, .. } |
+            NotifierConfig::Email { ref name, .. } |
+            NotifierConfig::Command { ref name, .. } => name,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct LocationConfig {
     pub name: String,
     pub ips: String,
+
+    // Jump hosts for all hosts in this location
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub proxy_jump: Option<String>,
+
+    // Seconds between two fetches of hosts in this location
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub refresh_delay: Option<u64>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct HttpConfig {
     pub port: u16,
+    // Address to listen on. Defaults to 0.0.0.0, every interface.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub bind: Option<String>,
+    // Serve HTTPS instead of HTTP
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub tls: Option<TlsConfig>,
+    // Without it, anyone reaching the port can do anything.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub auth: Option<HttpAuthConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct TlsConfig {
+    // PEM files, reloaded on SIGHUP. The certificate may include the chain.
+    pub certificate: String,
+    pub private_key: String,
+    // Also listen for plain HTTP on this port, redirecting to HTTPS
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub redirect_port: Option<u16>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct HttpAuthConfig {
+    // Logging in with HTTP Basic
+    #[serde(default, skip_serializing_if="Vec::is_empty")]
+    pub users: Vec<UserConfig>,
+    // Sent as `Authorization: Bearer <token>`, for scripts
+    #[serde(default, skip_serializing_if="Vec::is_empty")]
+    pub tokens: Vec<TokenConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct UserConfig {
+    pub name: String,
+    // bcrypt (`$2b$...`, like `htpasswd -B` makes) or argon2 (`$argon2...`)
+    pub password_hash: String,
+    // Defaults to `viewer`
+    #[serde(default)]
+    pub role: Role,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct TokenConfig {
+    pub name: String,
+    pub token: String,
+    // Defaults to `viewer`
+    #[serde(default)]
+    pub role: Role,
+}
+
+/// What someone logged in may do. Admins can do everything viewers can.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq,
+         PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    // See the dashboard, the status, alerts, metrics and history
+    Viewer,
+    // Also edit the config, refresh and stop
+    Admin,
+}
+
+impl Default for Role {
+    fn default() -> Self {
+        Role::Viewer
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct HostConfig {
     pub name: String,
     pub address: String,
@@ -53,30 +438,614 @@ pub struct HostConfig {
     pub auth: Option<AuthConfig>,
     #[serde(skip_serializing_if="Option::is_none")]
     pub location: Option<String>,
+
+    // Seconds between two fetches, overrides the location's and the global
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub refresh_delay: Option<u64>,
+
+    // How data is collected. Defaults to `ssh`.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub transport: Option<Transport>,
+
+    // Secret the host sends with its reports, for the `push` transport
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub token: Option<String>,
+
+    // What runs on the host to collect data. Defaults to `script`.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub collector: Option<Collector>,
+
+    // Comma-separated jump hosts, the closest to us first.
+    // Each is either the name of a host, or `[user@]address[:port]`.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub proxy_jump: Option<String>,
+
+    // Override the defaults from the `ssh` section
+    // Defaults to 22
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub port: Option<u16>,
+    // Seconds, defaults to 15
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub connect_timeout: Option<u64>,
+    // Seconds, defaults to 15
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub read_timeout: Option<u64>,
+    // Where the collector is uploaded. Defaults to the login directory.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub remote_dir: Option<String>,
+    // Prepended to the collector command, like `python3` or `sudo -n`
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub command_prefix: Option<String>,
+}
+
+impl Config {
+    /// Returns the jump hosts needed to reach this host, the closest first.
+    pub fn jump_chain(&self, host: &HostConfig) -> Result<Vec<HostConfig>> {
+        self.jump_chain_from(host, 0)
+    }
+
+    fn jump_chain_from(
+        &self,
+        host: &HostConfig,
+        depth: usize,
+    ) -> Result<Vec<HostConfig>> {
+        if depth > 8 {
+            bail!("too many nested jump hosts for {}", host.name);
+        }
+
+        let spec = match self.proxy_jump(host) {
+            Some(spec) if spec != "none" => spec,
+            _ => return Ok(Vec::new()),
+        };
+
+        let mut result = Vec::new();
+        for hop in spec.split(',').map(str::trim).filter(|h| !h.is_empty()) {
+            let hop = self.resolve_hop(hop);
+            // A bastion in a location doesn't go through itself.
+            if hop.name == host.name {
+                break;
+            }
+            // The first hop may have its own jump hosts.
+            if result.is_empty() {
+                result = self.jump_chain_from(&hop, depth + 1)?;
+            }
+            result.push(hop);
+        }
+        Ok(result)
+    }
+
+    /// Jump hosts set for this host, or for its location.
+    fn proxy_jump<'a>(&'a self, host: &'a HostConfig) -> Option<&'a String> {
+        host.proxy_jump.as_ref().or_else(|| {
+            self.location(host).and_then(|l| l.proxy_jump.as_ref())
+        })
+    }
+
+    /// The location the host is declared in, if any.
+    fn location(&self, host: &HostConfig) -> Option<&LocationConfig> {
+        host.location.as_ref().and_then(|location| {
+            self.locations.iter().find(|l| &l.name == location)
+        })
+    }
+
+    /// Names of the hosts that are new since `old`, or that must be
+    /// prepared again: their settings, location or jump hosts changed.
+    pub fn changed_hosts(&self, old: &Config) -> Vec<String> {
+        let everything = self.ssh != old.ssh || self.default != old.default ||
+            self.agent_path != old.agent_path;
+        self.hosts
+            .iter()
+            .filter(|host| {
+                let previous =
+                    match old.hosts.iter().find(|h| h.name == host.name) {
+                        Some(previous) => previous,
+                        None => return true,
+                    };
+                everything || *host != previous ||
+                    self.location(host) != old.location(previous) ||
+                    self.jump_chain(host).ok() !=
+                        old.jump_chain(previous).ok()
+            })
+            .map(|host| host.name.clone())
+            .collect()
+    }
+
+    /// Finds the host with this name, or parses `[user@]address[:port]`.
+    fn resolve_hop(&self, spec: &str) -> HostConfig {
+        if let Some(host) = self.hosts.iter().find(|h| h.name == spec) {
+            return self.resolve_host(host);
+        }
+
+        let (login, rest) = match spec.find('@') {
+            Some(i) => (Some(&spec[..i]), &spec[i + 1..]),
+            None => (None, spec),
+        };
+        let (address, port) = match rest.rfind(':') {
+            Some(i) => (&rest[..i], rest[i + 1..].parse().ok()),
+            None => (rest, None),
+        };
+
+        self.resolve_host(&HostConfig {
+            name: spec.to_string(),
+            address: address.to_string(),
+            port: port,
+            auth: login.map(|login| AuthConfig {
+                login: login.to_string(),
+                ..self.default.clone().unwrap_or_default()
+            }),
+            ..HostConfig::default()
+        })
+    }
+
+    /// Completes the host with its entry in the OpenSSH client config.
+    ///
+    /// `address` is looked up like an alias given to `ssh`. Settings from
+    /// farmview win: the `User` and `IdentityFile` of the host only fill the
+    /// login and keypair that neither the host nor `[default]` set.
+    pub fn resolve_host(&self, host: &HostConfig) -> HostConfig {
+        let path = match self.ssh {
+            Some(ref ssh) => ssh.config_file_path(),
+            None => SshConfig::default().config_file_path(),
+        };
+        let file = match path.and_then(|path| ssh_config::load(&path)) {
+            Some(file) => file,
+            None => return host.clone(),
+        };
+        let resolved = file.resolve(&host.address);
+
+        let mut result = host.clone();
+        if let Some(hostname) = resolved.hostname {
+            result.address = hostname;
+        }
+        result.port = host.port.or(resolved.port);
+        if self.proxy_jump(host).is_none() {
+            result.proxy_jump = resolved.proxy_jump;
+        }
+
+        // The first identity file that exists, like ssh would use.
+        let keypair = resolved
+            .identity_files
+            .iter()
+            .find(|path| expand_home(path).exists())
+            .cloned();
+        if host.auth.is_none() {
+            let mut auth = self.default.clone().unwrap_or_default();
+            let login = resolved.user.filter(|_| auth.login.is_empty());
+            let keypair = keypair.filter(|_| auth.keypair.is_none());
+            if login.is_some() || keypair.is_some() {
+                if let Some(login) = login {
+                    auth.login = login;
+                }
+                if keypair.is_some() {
+                    auth.keypair = keypair;
+                }
+                result.auth = Some(auth);
+            }
+        }
+        result
+    }
+}
+
+/// Connection settings for a host, with defaults applied.
+#[derive(Debug, Clone)]
+pub struct HostSettings {
+    pub port: u16,
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    pub remote_dir: Option<String>,
+    pub command_prefix: Option<String>,
+}
+
+impl HostConfig {
+    pub fn settings(&self, ssh: Option<&SshConfig>) -> HostSettings {
+        let default = SshConfig::default();
+        let ssh = ssh.unwrap_or(&default);
+        HostSettings {
+            port: self.port.or(ssh.port).unwrap_or(22),
+            connect_timeout: Duration::from_secs(
+                self.connect_timeout.or(ssh.connect_timeout).unwrap_or(15),
+            ),
+            read_timeout: Duration::from_secs(
+                self.read_timeout.or(ssh.read_timeout).unwrap_or(15),
+            ),
+            remote_dir: self.remote_dir.clone().or(ssh.remote_dir.clone()),
+            command_prefix: self.command_prefix
+                .clone()
+                .or(ssh.command_prefix.clone()),
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+impl HostSettings {
+    /// Path of the collector on the host.
+    pub fn remote_path(&self, collector: Collector) -> PathBuf {
+        match self.remote_dir {
+            Some(ref dir) => Path::new(dir).join(collector.remote_name()),
+            None => PathBuf::from(collector.remote_name()),
+        }
+    }
+
+    /// Command running the collector on the host.
+    pub fn command(&self, collector: Collector, iface: &str) -> String {
+        let path = self.remote_path(collector);
+        let path = path.to_string_lossy();
+        // Relative paths need to be explicit.
+        let path = if path.contains('/') {
+            path.into_owned()
+        } else {
+            format!("./{}", path)
+        };
+        match self.command_prefix {
+            Some(ref prefix) => format!("{} {} {}", prefix, path, iface),
+            None => format!("{} {}", path, iface),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    // Run the collector over SSH
+    Ssh,
+    // Collect in-process, for the machine farmview runs on
+    Local,
+    // The host POSTs its data to `/report/<name>`
+    Push,
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Transport::Ssh
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Collector {
+    // The `fetch.py` python script
+    Script,
+    // The native `farmview-agent` binary
+    Agent,
+}
+
+impl Default for Collector {
+    fn default() -> Self {
+        Collector::Script
+    }
+}
+
+impl Collector {
+    /// Name of the uploaded file on the host.
+    pub fn remote_name(&self) -> &'static str {
+        match *self {
+            Collector::Script => "fetch.py",
+            Collector::Agent => "farmview-agent",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct AuthConfig {
     pub login: String,
+    // Path to the private key
     #[serde(skip_serializing_if="Option::is_none")]
     pub keypair: Option<String>,
+    // Path to the public key, if it can't be derived from the private one
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub public_key: Option<String>,
+    // Environment variable holding the passphrase of the private key
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub passphrase_env: Option<String>,
+    // File holding the passphrase of the private key
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub passphrase_file: Option<String>,
     #[serde(skip_serializing_if="Option::is_none")]
     pub password: Option<String>,
+    // Use the ssh-agent from SSH_AUTH_SOCK
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub agent: Option<bool>,
+    // Methods to try, in order.
+    // Defaults to agent, then public key, then password, when configured.
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub methods: Option<Vec<AuthMethod>>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    Agent,
+    PublicKey,
+    Password,
+}
+
+impl AuthConfig {
+    /// Returns the methods to try, in order.
+    pub fn methods(&self) -> Vec<AuthMethod> {
+        if let Some(ref methods) = self.methods {
+            return methods.clone();
+        }
+
+        let mut result = Vec::new();
+        if self.agent == Some(true) {
+            result.push(AuthMethod::Agent);
+        }
+        if self.keypair.is_some() {
+            result.push(AuthMethod::PublicKey);
+        }
+        if self.password.is_some() {
+            result.push(AuthMethod::Password);
+        }
+        result
+    }
+
+    /// Reads the passphrase for the private key, if any.
+    pub fn passphrase(&self) -> Result<Option<String>> {
+        if let Some(ref var) = self.passphrase_env {
+            let passphrase = env::var(var)
+                .chain_err(|| format!("could not read ${}", var))?;
+            return Ok(Some(passphrase));
+        }
+        if let Some(ref path) = self.passphrase_file {
+            let mut passphrase = String::new();
+            fs::File::open(expand_home(path))
+                .and_then(|mut file| file.read_to_string(&mut passphrase))
+                .chain_err(|| format!("could not read {}", path))?;
+            let len = passphrase.trim_right_matches(&['\r', '\n'][..]).len();
+            passphrase.truncate(len);
+            return Ok(Some(passphrase));
+        }
+        Ok(None)
+    }
+}
+
+/// Reads and validates the config.
+///
+/// Fails with every problem found, with line numbers when possible.
 pub fn read_config<P: AsRef<Path>>(filename: P) -> Result<Config> {
     let mut file = fs::File::open(filename).chain_err(|| "could not open config file")?;
     let mut buffer = String::new();
     file.read_to_string(&mut buffer).chain_err(|| "could not read config")?;
-    toml::de::from_str(&buffer).chain_err(|| "could not parse config")
+    let config: Config =
+        toml::de::from_str(&buffer).chain_err(|| "could not parse config")?;
+
+    let problems = validate(&config, Some(&buffer));
+    if !problems.is_empty() {
+        let problems: Vec<String> =
+            problems.iter().map(|p| format!("  {}", p)).collect();
+        bail!("invalid config:\n{}", problems.join("\n"));
+    }
+    Ok(config)
 }
 
+/// Number of previous versions kept next to the config file.
+const BACKUPS: usize = 5;
+
+/// Writes the config, keeping the comments and layout of the current file.
+///
+/// The file is replaced atomically, and the previous version is kept as
+/// `<file>.1`. A file that cannot be loaded is never overwritten.
 pub fn write_config<P: AsRef<Path>>(filename: P,
                                     config: &Config)
                                     -> Result<()> {
-    let buffer = toml::ser::to_vec(config).chain_err(|| "could not serialize config")?;
-    let mut file = fs::File::create(filename).chain_err(|| "could not create config file")?;
-    file.write_all(&buffer).chain_err(|| "could not write config")?;
+    let path = filename.as_ref();
+    // toml::Value puts the plain values before the tables, as TOML needs.
+    let fresh = toml::Value::try_from(config)
+        .and_then(|value| toml::to_string(&value))
+        .chain_err(|| "could not serialize config")?;
+    let fresh: Document =
+        fresh.parse().chain_err(|| "could not serialize config")?;
+
+    let current = match fs::File::open(path) {
+        Ok(mut file) => {
+            let mut buffer = String::new();
+            file.read_to_string(&mut buffer)
+                .chain_err(|| "could not read config")?;
+            Some(buffer)
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).chain_err(|| "could not read config"),
+    };
+
+    let document = match current {
+        Some(current) => {
+            // It may be a half-edited version the user cares about. Check it
+            // the same way it is checked on load.
+            read_config(path).chain_err(|| {
+                format!("{:?} could not be loaded, not overwriting it", path)
+            })?;
+            let mut document: Document = current
+                .parse()
+                .chain_err(|| "could not parse config")?;
+            merge_table(document.as_table_mut(), fresh.as_table());
+            document
+        }
+        None => fresh,
+    };
+
+    write_atomic(path, document.to_string().as_bytes())
+}
 
+/// Updates `old` to the values of `new`, keeping its comments and layout.
+fn merge_table(old: &mut Table, new: &Table) {
+    let gone: Vec<String> = old.iter()
+        .map(|(key, _)| key.to_string())
+        .filter(|key| !new.contains_key(key))
+        .collect();
+    for key in gone {
+        old.remove(&key);
+    }
+
+    for (key, item) in new.iter() {
+        let merged = match (old.get_mut(key), item) {
+            (Some(&mut Item::Table(ref mut old)), &Item::Table(ref new)) => {
+                merge_table(old, new);
+                true
+            }
+            (Some(&mut Item::ArrayOfTables(ref mut old)),
+             &Item::ArrayOfTables(ref new)) => {
+                merge_array(old, new);
+                true
+            }
+            (Some(&mut Item::Value(ref mut old)), &Item::Value(ref new)) => {
+                merge_value(old, new.clone());
+                true
+            }
+            // Keep inline tables inline.
+            (Some(&mut Item::Value(ref mut old)), &Item::Table(ref new))
+                if old.is_inline_table() => {
+                let new = new.clone().into_inline_table();
+                merge_value(old, Value::InlineTable(new));
+                true
+            }
+            _ => false,
+        };
+        if !merged {
+            old.insert(key, item.clone());
+        }
+    }
+}
+
+/// Matches elements by name, so removing one keeps the others' comments.
+fn merge_array(old: &mut ArrayOfTables, new: &ArrayOfTables) {
+    let name = |table: &Table| {
+        table.get("name").and_then(|n| n.as_str()).map(String::from)
+    };
+    let new_names: Vec<Option<String>> = new.iter().map(&name).collect();
+
+    let mut merged = ArrayOfTables::new();
+    for (i, table) in new.iter().enumerate() {
+        let existing = old.iter()
+            .find(|old| new_names[i].is_some() && name(*old) == new_names[i])
+            // Probably renamed
+            .or_else(|| {
+                old.get(i).filter(|old| !new_names.contains(&name(*old)))
+            });
+        let mut result = existing.cloned().unwrap_or_else(Table::new);
+        merge_table(&mut result, table);
+        merged.push(result);
+    }
+    *old = merged;
+}
+
+/// Replaces the value if it changed, keeping the comments around it.
+fn merge_value(old: &mut Value, new: Value) {
+    let bare = |value: &Value| value.clone().decorated("", "").to_string();
+    if bare(old) != bare(&new) {
+        let decor = old.decor().clone();
+        *old = new;
+        *old.decor_mut() = decor;
+    }
+}
+
+/// Writes to a temporary file, then renames it over `path`.
+fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    let name = path.file_name()
+        .ok_or("invalid config file name")?
+        .to_string_lossy();
+    let tmp = path.with_file_name(format!(".{}.tmp", name));
+    {
+        let mut file = fs::File::create(&tmp)
+            .chain_err(|| "could not create config file")?;
+        // It may hold passwords: keep the permissions of the current file.
+        if let Ok(metadata) = fs::metadata(path) {
+            file.set_permissions(metadata.permissions())
+                .chain_err(|| "could not create config file")?;
+        }
+        file.write_all(content).chain_err(|| "could not write config")?;
+        file.sync_all().chain_err(|| "could not write config")?;
+    }
+
+    if path.exists() {
+        rotate_backups(path).chain_err(|| "could not back up config")?;
+    }
+    fs::rename(&tmp, path).chain_err(|| "could not replace config file")?;
+    // Make the rename itself durable.
+    if let Some(dir) = path.parent() {
+        if let Ok(dir) = fs::File::open(dir) {
+            dir.sync_all().ok();
+        }
+    }
+    Ok(())
+}
+
+/// Keeps the previous versions as `<file>.1` (the latest) to `<file>.N`.
+fn rotate_backups(path: &Path) -> io::Result<()> {
+    let backup = |i: usize| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}", i));
+        PathBuf::from(name)
+    };
+    for i in (1..BACKUPS).rev() {
+        if backup(i).exists() {
+            fs::rename(backup(i), backup(i + 1))?;
+        }
+    }
+    fs::copy(path, backup(1))?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::process;
+
+    #[test]
+    fn write_config_keeps_comments() {
+        let dir = env::temp_dir()
+            .join(format!("farmview-config-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("farmview.toml");
+        let original = "\
+# Our machines
+locations = []
+
+[ssh]
+    config_file = \"\"
+
+[default]
+    login = \"farmview\"
+    # Ask Bob for it
+    password = \"secret\"
+
+# The big one
+[[hosts]]
+    name = \"node1\"
+    address = \"node1.lan\" # behind the switch
+    iface = \"eth0\"
+
+[[hosts]]
+    name = \"node2\"
+    address = \"node2.lan\"
+    iface = \"eth0\"
+";
+        fs::write(&path, original).unwrap();
+
+        let mut config = read_config(&path).unwrap();
+        config.hosts[0].address = "10.0.0.1".to_string();
+        config.hosts.remove(1);
+        write_config(&path, &config).unwrap();
+
+        let written = fs::read_to_string(&path).unwrap();
+        let backup = fs::read_to_string(dir.join("farmview.toml.1")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let comments = [
+            "# Our machines",
+            "# Ask Bob for it",
+            "# The big one",
+            "\"10.0.0.1\" # behind the switch",
+        ];
+        for comment in &comments {
+            assert!(
+                written.contains(comment),
+                "{:?} lost:\n{}",
+                comment,
+                written
+            );
+        }
+        assert!(!written.contains("node2"));
+        assert_eq!(toml::from_str::<Config>(&written).unwrap(), config);
+        assert_eq!(backup, original);
+    }
+}
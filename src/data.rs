@@ -1,15 +1,76 @@
 use std::collections::HashMap;
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct Data {
     pub hosts: Vec<HostData>,
 
+    // Currently firing alerts
+    #[serde(default)]
+    pub alerts: Vec<AlertData>,
+
     pub update_time: String,
 }
 
+/// A firing alert, as shown to users.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlertData {
+    pub rule: String,
+    pub severity: String,
+    pub host: String,
+    pub subject: String,
+    pub message: String,
+    // Time (rfc3339) when the alert started firing
+    pub since: String,
+}
+
+/// State of the last fetch attempt for a host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HostState {
+    Ok,
+    Unreachable,
+    AuthFailed,
+    ScriptError,
+    TimedOut,
+    HostKeyMismatch,
+    // A pushing host didn't report recently
+    Stale,
+}
+
+impl Default for HostState {
+    fn default() -> Self {
+        HostState::Ok
+    }
+}
+
+impl HostState {
+    pub fn is_ok(&self) -> bool {
+        *self == HostState::Ok
+    }
+}
+
 /// This is what's produced by `fetch_data` regularly.
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct HostData {
+    // Name of the host in the config
+    #[serde(default)]
+    pub name: String,
+
+    #[serde(default)]
+    pub state: HostState,
+
+    // Why the last fetch failed, if it did
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub error: Option<String>,
+
+    // Time (rfc3339) of the last successful fetch
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub last_success: Option<String>,
+
+    // Time (rfc3339) of the next attempt, for failed hosts
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub next_retry: Option<String>,
+
     #[serde(skip_serializing_if="Option::is_none")]
     pub hostname: Option<String>,
 
@@ -26,6 +87,7 @@ pub struct HostData {
     #[serde(skip_serializing_if="Option::is_none")]
     pub memory: Option<MemoryData>,
 
+    #[serde(default)]
     pub disks: Vec<DiskData>,
 
     #[serde(skip_serializing_if="Option::is_none")]
@@ -35,7 +97,7 @@ pub struct HostData {
     pub network: Option<NetworkData>,
 }
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct MemoryData {
     // In kiB
     pub total: usize,
@@ -43,35 +105,40 @@ pub struct MemoryData {
     pub used: usize,
 }
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct PowerData {
     pub current: f32,
 }
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct Attribute {
     pub value: String,
     pub raw: String,
 }
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct DiskData {
     // In bytes
+    #[serde(skip_serializing_if="Option::is_none")]
     pub size: Option<usize>,
     // In bytes
+    #[serde(skip_serializing_if="Option::is_none")]
     pub available: Option<usize>,
     // In bytes
+    #[serde(skip_serializing_if="Option::is_none")]
     pub used: Option<usize>,
 
     pub mountpoint: String,
     pub name: String,
 
+    #[serde(skip_serializing_if="Option::is_none")]
     pub model: Option<String>,
 
+    #[serde(skip_serializing_if="Option::is_none")]
     pub attrs: Option<HashMap<String, Attribute>>,
 }
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct NetworkData {
     // In B/s
     #[serde(skip_serializing_if="Option::is_none")]
@@ -1,25 +1,87 @@
-use config::{AuthConfig, Config, HostConfig, LocationConfig};
-use data::{Data, HostData};
+use collect;
+use config::{Collector, Config, HostConfig, LocationConfig, Transport};
+use data::{HostData, HostState};
 use ips::IpBlock;
+use pool::SessionPool;
+use push::Reports;
 
+use std::env;
 use std::error;
+use std::fmt;
+use std::fs;
 use std::path;
-use std::io::{self, Write};
-use std::net::TcpStream;
 use std::time::Duration;
+use std::io::{self, Read, Write};
 
-use crossbeam;
 use rayon::prelude::*;
 use serde_json;
 use ssh2;
 use time;
 
+pub type BoxedError = Box<error::Error + Send + Sync>;
+
+/// Error encountered while fetching data from a host.
+///
+/// Carries the state the host should be reported in.
+#[derive(Debug)]
+pub struct FetchError {
+    pub state: HostState,
+    pub cause: BoxedError,
+}
+
+impl FetchError {
+    pub fn new<E: Into<BoxedError>>(state: HostState, cause: E) -> Self {
+        FetchError {
+            state: state,
+            cause: cause.into(),
+        }
+    }
+
+    /// Wraps an IO error, detecting timeouts.
+    pub fn io(default: HostState, err: io::Error) -> Self {
+        let state = match err.kind() {
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+                HostState::TimedOut
+            }
+            _ => default,
+        };
+        FetchError::new(state, err)
+    }
+
+    /// Wraps a libssh2 error, detecting timeouts and broken connections.
+    pub fn ssh(default: HostState, err: ssh2::Error) -> Self {
+        let state = match err.code() {
+            // LIBSSH2_ERROR_TIMEOUT, LIBSSH2_ERROR_SOCKET_TIMEOUT
+            -9 | -30 => HostState::TimedOut,
+            // LIBSSH2_ERROR_SOCKET_SEND, LIBSSH2_ERROR_SOCKET_DISCONNECT,
+            // LIBSSH2_ERROR_SOCKET_RECV, LIBSSH2_ERROR_BAD_SOCKET
+            -7 | -13 | -43 | -45 => HostState::Unreachable,
+            _ => default,
+        };
+        FetchError::new(state, err)
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {}", self.state, self.cause)
+    }
+}
+
+impl error::Error for FetchError {
+    fn description(&self) -> &str {
+        self.cause.description()
+    }
+}
+
 fn fetch_clean_host_data(
     host: &HostConfig,
-    default: Option<&AuthConfig>,
-    locations: &[LocationConfig],
-) -> Option<HostData> {
-    match fetch_host_data(host, default, locations) {
+    config: &Config,
+    pool: &SessionPool,
+    reports: &Reports,
+    now: &str,
+) -> HostData {
+    match fetch_host_data(host, config, pool, reports) {
         Ok(mut result) => {
             result.disks.retain(|data| {
                 host.ignored_disks
@@ -30,121 +92,92 @@ fn fetch_clean_host_data(
                     })
                     .unwrap_or(true)
             });
-            Some(result)
+            result.name = host.name.clone();
+            result.state = HostState::Ok;
+            // Reports are as recent as when they were received.
+            if result.last_success.is_none() {
+                result.last_success = Some(now.to_string());
+            }
+            result
         }
         Err(e) => {
-            println!("Error with {}: {:?}", host.name, e);
-            None
+            println!("Error with {}: {}", host.name, e);
+            // Still report the host, so it doesn't vanish from the view.
+            HostData {
+                name: host.name.clone(),
+                hostname: Some(host.name.clone()),
+                location: host.location.clone(),
+                state: e.state,
+                error: Some(e.cause.to_string()),
+                ..HostData::default()
+            }
         }
     }
 }
 
-fn fill_result(result: &mut Vec<Option<HostData>>, config: &Config) {
-    let default = config.default.as_ref();
-    let locations = &config.locations;
-    let iter = result.iter_mut().zip(config.hosts.iter());
-    crossbeam::scope(|scope| for (r, host) in iter {
-        scope.spawn(move || {
-            *r = fetch_clean_host_data(host, default, locations);
-        });
-    });
-}
-
-pub fn fetch_data(config: &Config) -> Data {
-    // Fetch each host in parallel
-    let mut result: Vec<_> = config.hosts.iter().map(|_| None).collect();
-    fill_result(&mut result, config);
-    let mut result: Vec<_> = result.into_iter().filter_map(|r| r).collect();
-
-    let empty = String::new();
-    result.sort_by(|a, b| {
-        a.location
-            .as_ref()
-            .unwrap_or(&empty)
-            .cmp(b.location.as_ref().unwrap_or(&empty))
-    });
-
+/// Fetches data from a single host.
+///
+/// If it could not be reached, the result has an error state.
+pub fn fetch_host(
+    host: &HostConfig,
+    config: &Config,
+    pool: &SessionPool,
+    reports: &Reports,
+) -> HostData {
     let now = format!("{}", time::now().rfc3339());
-    Data {
-        hosts: result,
-        update_time: now,
-    }
+    fetch_clean_host_data(host, config, pool, reports, &now)
 }
 
-fn authenticate(
-    sess: &mut ssh2::Session,
+fn fetch_host_data(
     host: &HostConfig,
-    default: Option<&AuthConfig>,
-) -> Result<(), ssh2::Error> {
-    // Do we have an authentication? Or do we have a default one?
-    if let Some(auth) = host.auth.as_ref().or(default) {
-        if let Some(ref password) = auth.password {
-            // Maybe we log in with a password?
-            sess.userauth_password(&auth.login, password)?;
-        } else if let Some(ref keypair) = auth.keypair {
-            // Or maybe with an identity file?
-            sess.userauth_pubkey_file(
-                &auth.login,
-                None,
-                path::Path::new(keypair),
-                None,
-            )?;
+    config: &Config,
+    pool: &SessionPool,
+    reports: &Reports,
+) -> Result<HostData, FetchError> {
+    let mut result = match host.transport.unwrap_or_default() {
+        Transport::Ssh => pool.with_session(host, config, |sess| {
+            run_collector(sess, host, config)
+        })?,
+        Transport::Local => collect::collect(&host.iface),
+        Transport::Push => {
+            let stale_after = config.stale_after.unwrap_or(300);
+            reports.get(&host.name, Duration::from_secs(stale_after))?
         }
-    }
-    Ok(())
-}
-
-type BoxedError = Box<error::Error + Send + Sync>;
-
-fn connect(
-    host: &HostConfig,
-    default: Option<&AuthConfig>,
-) -> Result<(TcpStream, ssh2::Session), BoxedError> {
-    // TODO: Don't panic on error
-    let tcp = TcpStream::connect((&*host.address, 22))?;
-    tcp.set_read_timeout(Some(Duration::from_secs(15)))?;
-    tcp.set_write_timeout(Some(Duration::from_secs(15)))?;
-
-    // An error here means something very wrong is going on.
-    let mut sess = ssh2::Session::new().ok_or_else(|| {
-        io::Error::new(io::ErrorKind::Other, "Could not create ssh session")
-    })?;
-    // 15,000 ms = 15s
-    sess.set_timeout(15_000);
-    sess.handshake(&tcp)?;
-    authenticate(&mut sess, host, default)?;
-
-    Ok((tcp, sess))
-}
-
+    };
 
-fn fetch_host_data(
-    host: &HostConfig,
-    default: Option<&AuthConfig>,
-    locations: &[LocationConfig],
-) -> Result<HostData, Box<error::Error + Send + Sync>> {
-    // `tcp` needs to survive the scope,
-    // because on drop it closes the connection.
-    // But we're not using it, so an underscore
-    // will avoid `unused` warnings.
-    let (_tcp, sess) = connect(host, default)?;
-
-    let mut channel = sess.channel_session()?;
-    channel.exec(&format!("./fetch.py {}", host.iface))?;
-    // A JSON error here means the script went mad.
-    // ... or just a connection issue maybe?
-    let mut result: HostData = serde_json::from_reader(channel)?;
     let location = result
         .network
         .as_ref()
         .and_then(|n| n.ip.as_ref())
-        .and_then(|ip| find_location(ip, locations));
-
+        .and_then(|ip| find_location(ip, &config.locations));
     result.location = host.location.clone().or(location);
 
     Ok(result)
 }
 
+fn run_collector(
+    sess: &ssh2::Session,
+    host: &HostConfig,
+    config: &Config,
+) -> Result<HostData, FetchError> {
+    let script_error = |e| FetchError::ssh(HostState::ScriptError, e);
+    let mut channel = sess.channel_session().map_err(script_error)?;
+    let collector = host.collector.unwrap_or_default();
+    let settings = host.settings(config.ssh.as_ref());
+    channel
+        .exec(&settings.command(collector, &host.iface))
+        .map_err(script_error)?;
+    // A JSON error here means the script went mad,
+    // an IO error that the connection broke.
+    serde_json::from_reader(channel).map_err(|e| {
+        if e.is_io() {
+            FetchError::io(HostState::Unreachable, e.into())
+        } else {
+            FetchError::new(HostState::ScriptError, e)
+        }
+    })
+}
+
 fn find_location(ip: &str, locations: &[LocationConfig]) -> Option<String> {
     locations
         .iter()
@@ -153,37 +186,86 @@ fn find_location(ip: &str, locations: &[LocationConfig]) -> Option<String> {
 }
 
 fn match_ip(ip: &str, mask: &str) -> bool {
-    IpBlock::new(mask).matches(ip)
+    mask.parse::<IpBlock>()
+        .map(|block| block.matches(ip))
+        .unwrap_or(false)
+}
+
+/// Reads the `farmview-agent` binary to upload.
+fn read_agent(config: &Config) -> io::Result<Vec<u8>> {
+    let path = match config.agent_path {
+        Some(ref path) => path::PathBuf::from(path),
+        None => env::current_exe()?.with_file_name("farmview-agent"),
+    };
+    let mut buffer = Vec::new();
+    fs::File::open(path)?.read_to_end(&mut buffer)?;
+    Ok(buffer)
 }
 
 fn prepare_host(
     host: &HostConfig,
-    default: Option<&AuthConfig>,
-) -> Result<(), Box<error::Error + Send + Sync>> {
+    config: &Config,
+    pool: &SessionPool,
+    agent: Option<&[u8]>,
+) -> Result<(), BoxedError> {
+    // Nothing to upload.
+    if host.transport.unwrap_or_default() != Transport::Ssh {
+        return Ok(());
+    }
+
     // Directly include the script in the executable
-    let script_data = include_str!("../data/fetch.py");
-
-    // `tcp` needs to survive the scope, because on drop it closes the connection.
-    let (_tcp, sess) = connect(host, default)?;
-    let mut remote_file = sess.scp_send(
-        path::Path::new("fetch.py"),
-        0o755,
-        script_data.len() as u64,
-        None,
-    )?;
-    remote_file.write_all(script_data.as_bytes())?;
+    let script_data = include_str!("../data/fetch.py").as_bytes();
+
+    let collector = host.collector.unwrap_or_default();
+    let data = match collector {
+        Collector::Script => script_data,
+        Collector::Agent => agent.ok_or("farmview-agent binary not found")?,
+    };
+
+    let settings = host.settings(config.ssh.as_ref());
+    pool.with_session(host, config, |sess| {
+        let mut remote_file = sess.scp_send(
+            &settings.remote_path(collector),
+            0o755,
+            data.len() as u64,
+            None,
+        ).map_err(|e| FetchError::ssh(HostState::ScriptError, e))?;
+        remote_file
+            .write_all(data)
+            .map_err(|e| FetchError::io(HostState::Unreachable, e))
+    })?;
     Ok(())
 }
 
+/// Uploads the collector to these hosts of the config.
 pub fn prepare_hosts(
+    hosts: &[&HostConfig],
     config: &Config,
-) -> Vec<Option<Box<error::Error + Send + Sync>>> {
+    pool: &SessionPool,
+) -> Vec<Option<BoxedError>> {
+    // Only load the agent if someone needs it.
+    let agent = if hosts
+        .iter()
+        .any(|host| {
+            host.transport.unwrap_or_default() == Transport::Ssh &&
+                host.collector == Some(Collector::Agent)
+        })
+    {
+        read_agent(config)
+            .map_err(|e| println!("Could not read farmview-agent: {}", e))
+            .ok()
+    } else {
+        None
+    };
+
     let mut result = Vec::new();
     // Prepare each host in parallel
-    config
-        .hosts
+    hosts
         .par_iter()
-        .map(|host| prepare_host(host, config.default.as_ref()).err())
+        .map(|host| {
+            let agent = agent.as_ref().map(|a| &a[..]);
+            prepare_host(host, config, pool, agent).err()
+        })
         .collect_into(&mut result);
     result
 }
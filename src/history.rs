@@ -0,0 +1,293 @@
+//! On-disk history of the collected data.
+//!
+//! Each host has its own directory, with one append-only segment per day.
+//! Each line of a segment is a JSON-encoded `Record`.
+
+use config::HistoryConfig;
+use data::{Data, HostData};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde_json::{self, Value};
+use time;
+
+const DAY: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    // Unix timestamp, in seconds
+    time: u64,
+    data: HostData,
+}
+
+/// A single point of a time series.
+#[derive(Serialize, Debug)]
+pub struct Point(pub u64, pub f64);
+
+pub fn now() -> u64 {
+    time::get_time().sec as u64
+}
+
+/// Keeps track of what was written, to respect the resolution.
+pub struct History {
+    // Last time we recorded something for each host
+    last_record: Mutex<HashMap<String, u64>>,
+    // Last day we cleaned up old segments
+    last_maintenance: Mutex<u64>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        History {
+            last_record: Mutex::new(HashMap::new()),
+            last_maintenance: Mutex::new(0),
+        }
+    }
+
+    /// Records the freshly fetched data.
+    pub fn record(&self, config: &HistoryConfig, data: &Data) {
+        let now = now();
+        let resolution = config.resolution.unwrap_or(0);
+
+        {
+            let mut last_record = self.last_record.lock().unwrap();
+            // Only successful fetches carry any metric.
+            for host in data.hosts.iter().filter(|h| h.state.is_ok()) {
+                if let Some(&last) = last_record.get(&host.name) {
+                    if now < last + resolution {
+                        continue;
+                    }
+                }
+                last_record.insert(host.name.clone(), now);
+
+                if let Err(e) = append(config, now, host) {
+                    println!("Error recording {}: {}", host.name, e);
+                }
+            }
+        }
+
+        // Once a day, enforce retention and downsampling.
+        let mut last_maintenance = self.last_maintenance.lock().unwrap();
+        if *last_maintenance != now / DAY {
+            *last_maintenance = now / DAY;
+            if let Err(e) = maintain(config, now) {
+                println!("Error cleaning up history: {}", e);
+            }
+        }
+    }
+}
+
+/// Replaces anything that doesn't belong in a file name.
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+fn host_dir(config: &HistoryConfig, host: &str) -> PathBuf {
+    Path::new(&config.path).join(sanitize(host))
+}
+
+fn segment_path(dir: &Path, day: u64) -> PathBuf {
+    dir.join(format!("{}.jsonl", day))
+}
+
+/// Parses the day out of a segment file name.
+fn segment_day(path: &Path) -> Option<u64> {
+    if path.extension().and_then(|e| e.to_str()) != Some("jsonl") {
+        return None;
+    }
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.parse().ok())
+}
+
+fn append(
+    config: &HistoryConfig,
+    now: u64,
+    host: &HostData,
+) -> io::Result<()> {
+    let dir = host_dir(config, &host.name);
+    fs::create_dir_all(&dir)?;
+
+    let record = Record {
+        time: now,
+        data: host.clone(),
+    };
+    let mut line = serde_json::to_vec(&record)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    line.push(b'\n');
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(&dir, now / DAY))?;
+    file.write_all(&line)
+}
+
+fn read_segment(path: &Path) -> io::Result<Vec<Record>> {
+    let file = fs::File::open(path)?;
+    let mut result = Vec::new();
+    for line in BufReader::new(file).lines() {
+        // A crash may leave a truncated last line, just skip it.
+        if let Ok(record) = serde_json::from_str(&line?) {
+            result.push(record);
+        }
+    }
+    Ok(result)
+}
+
+/// Removes expired segments, and downsamples old ones.
+fn maintain(config: &HistoryConfig, now: u64) -> io::Result<()> {
+    let today = now / DAY;
+    let retention = config.retention_days.unwrap_or(30);
+    let downsample_after = config.downsample_after_days;
+    let downsample_resolution = config.downsample_resolution.unwrap_or(3600);
+
+    let hosts = match fs::read_dir(&config.path) {
+        Ok(hosts) => hosts,
+        // Nothing recorded yet
+        Err(_) => return Ok(()),
+    };
+
+    for host in hosts {
+        for segment in fs::read_dir(host?.path())? {
+            let path = segment?.path();
+            let day = match segment_day(&path) {
+                Some(day) => day,
+                None => continue,
+            };
+
+            let age = today.saturating_sub(day);
+            if age > retention {
+                fs::remove_file(&path)?;
+            } else if downsample_after.map_or(false, |after| age > after) {
+                downsample(&path, downsample_resolution)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Keeps at most one record per `resolution` seconds in this segment.
+fn downsample(path: &Path, resolution: u64) -> io::Result<()> {
+    let records = read_segment(path)?;
+    let mut kept = Vec::new();
+    let mut last_bucket = None;
+    for record in records {
+        let bucket = record.time / resolution.max(1);
+        if last_bucket != Some(bucket) {
+            last_bucket = Some(bucket);
+            kept.push(record);
+        }
+    }
+
+    let tmp = path.with_extension("jsonl.tmp");
+    {
+        let mut file = fs::File::create(&tmp)?;
+        for record in &kept {
+            serde_json::to_writer(&mut file, record)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            file.write_all(b"\n")?;
+        }
+        file.sync_all()?;
+    }
+    fs::rename(tmp, path)
+}
+
+/// Follows a dotted path like `memory.used` or `disks./home.used`.
+///
+/// Array elements are selected either by index, or by `mountpoint`/`name`.
+fn extract(value: &Value, metric: &str) -> Option<f64> {
+    let mut current = value;
+    for key in metric.split('.') {
+        current = match *current {
+            Value::Object(ref map) => map.get(key)?,
+            Value::Array(ref items) => match key.parse::<usize>() {
+                Ok(i) => items.get(i)?,
+                Err(_) => items.iter().find(|item| {
+                    item.get("mountpoint").and_then(Value::as_str) ==
+                        Some(key) ||
+                        item.get("name").and_then(Value::as_str) == Some(key)
+                })?,
+            },
+            _ => return None,
+        };
+    }
+
+    match *current {
+        Value::Number(ref n) => n.as_f64(),
+        // SMART attributes are stored as strings
+        Value::String(ref s) => s.split_whitespace().next()?.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Returns the time series for the given metric.
+///
+/// If `step` is given, points are averaged over buckets of `step` seconds.
+pub fn query(
+    config: &HistoryConfig,
+    host: &str,
+    metric: &str,
+    from: u64,
+    to: u64,
+    step: Option<u64>,
+) -> io::Result<Vec<Point>> {
+    let dir = host_dir(config, host);
+    let mut points = Vec::new();
+
+    for day in (from / DAY)..(to / DAY + 1) {
+        let path = segment_path(&dir, day);
+        if !path.exists() {
+            continue;
+        }
+        for record in read_segment(&path)? {
+            if record.time < from || record.time > to {
+                continue;
+            }
+            let value = serde_json::to_value(&record.data)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            if let Some(v) = extract(&value, metric) {
+                points.push(Point(record.time, v));
+            }
+        }
+    }
+
+    Ok(match step {
+        Some(step) if step > 0 => average(points, step),
+        _ => points,
+    })
+}
+
+fn average(points: Vec<Point>, step: u64) -> Vec<Point> {
+    let mut result: Vec<Point> = Vec::new();
+    let mut count = 0;
+    for Point(time, value) in points {
+        let bucket = time - time % step;
+        match result.last_mut() {
+            Some(&mut Point(t, ref mut sum)) if t == bucket => {
+                *sum += value;
+                count += 1;
+                continue;
+            }
+            _ => (),
+        }
+        if let Some(last) = result.last_mut() {
+            last.1 /= count as f64;
+        }
+        result.push(Point(bucket, value));
+        count = 1;
+    }
+    if let Some(last) = result.last_mut() {
+        last.1 /= count as f64;
+    }
+    result
+}
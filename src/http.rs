@@ -1,13 +1,30 @@
-use config::{Config, HostConfig};
-use server;
+use api::{self, ConfigSync};
+use auth;
+use config::{Config, HttpConfig};
+use errors;
+use history;
+use metrics;
+use data::Data;
+use server::{self, Event, ReportError, Server};
+use signals;
+use tls::Tls;
+use watch;
 // use errors::*;
 
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::sync::{mpsc, Arc};
 use std::collections::HashMap;
+use std::time::Duration;
 use hyper;
+use hyper::header::{Authorization, Bearer, ContentLength, Host, Location};
+use hyper::server::{Handler, Request, Response};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
 use serde_json;
 use reroute;
 use tera;
+use error_chain::ChainedError;
 
 fn tier(value: tera::Value,
         params: HashMap<String, tera::Value>)
@@ -31,17 +48,289 @@ fn tier(value: tera::Value,
     })
 }
 
+/// Escapes an URL component.
+pub fn percent_encode(input: &str) -> String {
+    let mut result = String::new();
+    for b in input.bytes() {
+        if b.is_ascii_alphanumeric() || b"-_.~".contains(&b) {
+            result.push(b as char);
+        } else {
+            result.push_str(&format!("%{:02X}", b));
+        }
+    }
+    result
+}
+
+/// Decodes `%XX` escapes (and `+` as space) from an URL component.
+pub fn percent_decode(input: &str) -> String {
+    let mut bytes = Vec::new();
+    let mut iter = input.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex: Vec<u8> = iter.by_ref().take(2).collect();
+                let decoded = String::from_utf8(hex.clone())
+                    .ok()
+                    .and_then(|h| u8::from_str_radix(&h, 16).ok());
+                match decoded {
+                    Some(d) => bytes.push(d),
+                    None => {
+                        bytes.push(b'%');
+                        bytes.extend(hex);
+                    }
+                }
+            }
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Parses the query string of the request.
+fn query_params(req: &Request) -> HashMap<String, String> {
+    let path = match req.uri {
+        RequestUri::AbsolutePath(ref path) => path.as_str(),
+        _ => "",
+    };
+    let query = match path.find('?') {
+        Some(i) => &path[i + 1..],
+        None => "",
+    };
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut kv = pair.splitn(2, '=');
+            let key = percent_decode(kv.next().unwrap_or(""));
+            let value = percent_decode(kv.next().unwrap_or(""));
+            (key, value)
+        })
+        .collect()
+}
+
+/// Sends an error message with the given status.
+fn send_error(mut resp: Response, status: StatusCode, message: &str) {
+    *resp.status_mut() = status;
+    resp.send(message.as_bytes()).ok();
+}
+
+/// Largest report a host may push, in bytes.
+const MAX_REPORT_SIZE: u64 = 1 << 20;
+
+fn send_report_error(resp: Response, error: ReportError) {
+    let (status, message) = match error {
+        ReportError::UnknownHost => (StatusCode::NotFound, "Host not found"),
+        ReportError::NotPushing => {
+            (StatusCode::Conflict, "Host does not use the push transport")
+        }
+        ReportError::BadToken => (StatusCode::Unauthorized, "Invalid token"),
+    };
+    send_error(resp, status, message);
+}
+
+/// Wraps the router, to check who is asking, and to refuse requests once
+/// the server is stopping.
+struct App {
+    router: reroute::Router,
+    server: Arc<server::Server>,
+}
+
+impl App {
+    /// Checks the credentials of the request against `[http.auth]`.
+    ///
+    /// Gives the response back if the request may go on, otherwise sends the
+    /// error.
+    fn authorize<'a>(
+        &self,
+        req: &Request,
+        mut resp: Response<'a>,
+    ) -> Option<Response<'a>> {
+        let config = self.server
+            .current_conf()
+            .http
+            .as_ref()
+            .and_then(|http| http.auth.clone());
+        let config = match config {
+            Some(config) => config,
+            None => return Some(resp),
+        };
+        let path = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path.as_str(),
+            _ => "",
+        };
+        let required = match auth::required_role(&req.method, path) {
+            Some(required) => required,
+            None => return Some(resp),
+        };
+
+        match auth::authenticate(&config, &req.headers) {
+            Some(role) if role >= required => Some(resp),
+            Some(_) => {
+                send_error(resp, StatusCode::Forbidden, "Not allowed");
+                None
+            }
+            None => {
+                resp.headers_mut().set_raw(
+                    "WWW-Authenticate",
+                    vec![b"Basic realm=\"farmview\"".to_vec()],
+                );
+                send_error(resp,
+                           StatusCode::Unauthorized,
+                           "Authentication required");
+                None
+            }
+        }
+    }
+}
+
+impl Handler for App {
+    fn handle<'a, 'k>(
+        &'a self,
+        req: Request<'a, 'k>,
+        resp: Response<'a>,
+    ) {
+        if self.server.is_stopping() {
+            return send_error(resp,
+                              StatusCode::ServiceUnavailable,
+                              "Shutting down");
+        }
+        if let Some(resp) = self.authorize(&req, resp) {
+            self.router.handle(req, resp);
+        }
+    }
+}
+
+/// Sends plain HTTP requests to the same path over HTTPS.
+struct Redirect {
+    // Where HTTPS is served
+    port: u16,
+}
+
+impl Handler for Redirect {
+    fn handle<'a, 'k>(
+        &'a self,
+        req: Request<'a, 'k>,
+        mut resp: Response<'a>,
+    ) {
+        let host = req.headers
+            .get::<Host>()
+            .map_or("localhost", |host| host.hostname.as_str());
+        let path = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path.as_str(),
+            _ => "/",
+        };
+        let port = match self.port {
+            443 => String::new(),
+            port => format!(":{}", port),
+        };
+        *resp.status_mut() = StatusCode::MovedPermanently;
+        resp.headers_mut()
+            .set(Location(format!("https://{}{}{}", host, port, path)));
+        resp.send(b"").ok();
+    }
+}
+
+/// Renders the dashboard, from the same parts `/events` sends.
+fn render_index(tera: &tera::Tera, data: &Data) -> String {
+    let cards: Vec<String> = data.hosts
+        .iter()
+        .map(|host| {
+            tera.render("host.html", &json!({ "host": host })).unwrap()
+        })
+        .collect();
+    let alerts = json!({ "alerts": data.alerts });
+    let updated = json!({ "update_time": data.update_time });
+    let context = json!({
+        "cards": cards,
+        "alerts": tera.render("alerts.html", &alerts).unwrap(),
+        "updated": tera.render("updated.html", &updated).unwrap(),
+    });
+    tera.render("index.html", &context).unwrap()
+}
+
+/// Formats an event for `/events`, with the HTML the dashboard shows.
+fn render_event(tera: &tera::Tera, server: &Server, event: Event) -> String {
+    let (name, body) = match event {
+        Event::Host(host) => {
+            let card = json!({ "host": host });
+            let updated =
+                json!({ "update_time": server.latest_data().update_time });
+            ("host", json!({
+                "host": host,
+                "html": tera.render("host.html", &card).unwrap(),
+                "updated": tera.render("updated.html", &updated).unwrap(),
+            }))
+        }
+        Event::Removed(name) => ("removed", json!({ "name": name })),
+        Event::Alerts(alerts) => {
+            let context = json!({ "alerts": alerts });
+            ("alerts", json!({
+                "alerts": alerts,
+                "html": tera.render("alerts.html", &context).unwrap(),
+            }))
+        }
+    };
+    format!("event: {}\ndata: {}\n\n", name, body)
+}
+
+/// Seconds between two comments on idle event streams.
+const EVENTS_KEEPALIVE: u64 = 15;
+
+/// Each event stream keeps a thread busy.
+const MAX_EVENT_STREAMS: usize = 32;
+
+/// Threads answering requests, including the event streams.
+const THREADS: usize = MAX_EVENT_STREAMS + 16;
+
+/// Seconds to wait for the fetches in progress when stopping.
+const STOP_TIMEOUT: u64 = 20;
+
 // We'll use a server::Server to actually process anything.
 // Here, we just set up the http handlers to redirect queries.
 // We do the translation from request to json to actual types.
-pub fn serve<F>(config: Config, config_sync: F)
-    where F: 'static + Send + Sync + Fn(&Config)
+//
+// The config file at `config_path` is reloaded when it changes, and with the
+// TLS certificate on SIGHUP.
+//
+// Returns once the server is stopped, by `/stop` or a signal: `true` if
+// everything finished cleanly.
+pub fn serve<F>(config: Config, config_path: PathBuf, config_sync: F) -> bool
+    where F: 'static + Send + Sync + Fn(&Config) -> errors::Result<()>
 {
-    let port = config.http.as_ref().map_or(8080, |http| http.port);
-    let config_sync_ = Arc::new(config_sync);
+    let http = config.http.clone().unwrap_or_else(|| HttpConfig {
+        port: 8080,
+        ..HttpConfig::default()
+    });
+    if http.auth.is_none() {
+        println!("No [http.auth] section: anyone can use the web interface.");
+    }
+    let bind = http.bind.clone().unwrap_or_else(|| "0.0.0.0".to_string());
+    let tls = match http.tls.clone().map(Tls::new) {
+        Some(Ok(tls)) => Some(tls),
+        Some(Err(e)) => {
+            print!("{}", e.display_chain());
+            return false;
+        }
+        None => None,
+    };
+    let config_sync: ConfigSync = Arc::new(config_sync);
 
     // This is an Arc
     let server = server::Server::new(config);
+    let server_ = server.clone();
+    let tls_ = tls.clone();
+    let config_path_ = config_path.clone();
+    let reload = move || {
+        server_.reload(&config_path_);
+        if let Some(ref tls) = tls_ {
+            tls.reload();
+        }
+    };
+    if let Err(e) = signals::spawn(server.clone(), reload) {
+        println!("Could not handle signals: {}", e);
+    }
+    watch::spawn(server.clone(), config_path);
 
     let mut builder = reroute::RouterBuilder::new();
 
@@ -55,111 +344,297 @@ pub fn serve<F>(config: Config, config_sync: F)
     tera.add_raw_template("style.css",
                       include_str!("../data/templates/style.css"))
         .unwrap();
+    tera.add_raw_template("host.html",
+                      include_str!("../data/templates/host.html"))
+        .unwrap();
+    tera.add_raw_template("alerts.html",
+                      include_str!("../data/templates/alerts.html"))
+        .unwrap();
+    tera.add_raw_template("updated.html",
+                      include_str!("../data/templates/updated.html"))
+        .unwrap();
+    let tera = Arc::new(tera);
+
     let server_ = server.clone();
+    let tera_ = tera.clone();
     builder.get("^/$", move |_, resp, _| {
         // Return plain HTML
         let data = server_.latest_data();
-        let content = tera.render("index.html", &*data).unwrap();
+        let content = render_index(&tera_, &data);
         resp.send(content.as_bytes()).ok();
     });
 
+    let server_ = server.clone();
+    builder.get("^/events$", move |_, mut resp, _| {
+        if server_.subscribers() >= MAX_EVENT_STREAMS {
+            return send_error(resp,
+                              StatusCode::ServiceUnavailable,
+                              "Too many event streams");
+        }
+        let events = server_.subscribe();
+        resp.headers_mut()
+            .set_raw("Content-Type", vec![b"text/event-stream".to_vec()]);
+        resp.headers_mut()
+            .set_raw("Cache-Control", vec![b"no-cache".to_vec()]);
+        let mut stream = match resp.start() {
+            Ok(stream) => stream,
+            Err(_) => return,
+        };
+
+        let keepalive = Duration::from_secs(EVENTS_KEEPALIVE);
+        loop {
+            let message = match events.recv_timeout(keepalive) {
+                Ok(event) => render_event(&tera, &server_, event),
+                // Also notices when the client left.
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    ": keepalive\n\n".to_string()
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            };
+            let sent = stream
+                .write_all(message.as_bytes())
+                .and_then(|_| stream.flush());
+            if sent.is_err() {
+                break;
+            }
+        }
+        stream.end().ok();
+    });
+
     let server_ = server.clone();
     builder.get("^/status$", move |_, resp, _| {
         let data = server_.latest_data();
         resp.send(&serde_json::to_vec(&*data).unwrap()).ok();
     });
 
+    let server_ = server.clone();
+    builder.get("^/alerts$", move |_, resp, _| {
+        let data = server_.latest_data();
+        resp.send(&serde_json::to_vec(&data.alerts).unwrap()).ok();
+    });
+
+    let server_ = server.clone();
+    builder.get("^/metrics$", move |_, mut resp, _| {
+        let durations = server_.durations();
+        let stats = server_.pool_stats();
+        let content =
+            metrics::render(&server_.latest_data(), &durations, &stats);
+        resp.headers_mut().set_raw(
+            "Content-Type",
+            vec![b"text/plain; version=0.0.4".to_vec()],
+        );
+        resp.send(content.as_bytes()).ok();
+    });
+
     let server_ = server.clone();
     builder.get("^/config$", move |_, resp, _| {
         let config = server_.current_conf();
-        resp.send(&serde_json::to_vec(&*config).unwrap()).ok();
+        let mut redacted = config.clone();
+        api::redact(&mut redacted);
+        let mut value = serde_json::to_value(&redacted).unwrap();
+        // Show what the OpenSSH config made of each host.
+        if let Some(hosts) = value["hosts"].as_array_mut() {
+            for (host, entry) in config.hosts.iter().zip(hosts) {
+                let resolved = config.resolve_host(host);
+                let settings = resolved.settings(config.ssh.as_ref());
+                entry["resolved"] = json!({
+                    "address": resolved.address,
+                    "port": settings.port,
+                    "login": resolved.auth
+                        .as_ref()
+                        .or(config.default.as_ref())
+                        .map(|auth| &auth.login),
+                    "keypair": resolved.auth
+                        .as_ref()
+                        .or(config.default.as_ref())
+                        .and_then(|auth| auth.keypair.as_ref()),
+                    "proxy_jump": config.jump_chain(&resolved)
+                        .ok()
+                        .map(|chain| {
+                            chain.iter()
+                                .map(|hop| hop.name.clone())
+                                .collect::<Vec<_>>()
+                        }),
+                });
+            }
+        }
+        resp.send(&serde_json::to_vec(&value).unwrap()).ok();
+    });
+
+    let server_ = server.clone();
+    builder.get(r"^/history/([^?]+)", move |req, resp, captures| {
+        let host = percent_decode(&captures.unwrap()[1]);
+        let params = query_params(&req);
+
+        let config = match server_.current_conf().history.clone() {
+            Some(config) => config,
+            None => {
+                return send_error(resp,
+                                  StatusCode::NotFound,
+                                  "History is not enabled");
+            }
+        };
+        let metric = match params.get("metric") {
+            Some(metric) => metric,
+            None => {
+                return send_error(resp,
+                                  StatusCode::BadRequest,
+                                  "Missing metric");
+            }
+        };
+        let number =
+            |key: &str| params.get(key).and_then(|v| v.parse::<u64>().ok());
+        // Defaults to the last day
+        let to = number("to").unwrap_or_else(history::now);
+        let from = number("from").unwrap_or(to.saturating_sub(24 * 3600));
+        let step = number("step");
+
+        match history::query(&config, &host, metric, from, to, step) {
+            Ok(points) => {
+                let body = json!({
+                    "host": host,
+                    "metric": metric,
+                    "points": points,
+                });
+                resp.send(&serde_json::to_vec(&body).unwrap()).ok();
+            }
+            Err(e) => {
+                send_error(resp,
+                           StatusCode::InternalServerError,
+                           &format!("Could not read history: {}", e))
+            }
+        }
     });
 
     // All those are just json API
 
     let server_ = server.clone();
-    builder.post("^/refresh$", move |_, resp, _| {
-        server_.refresh();
-        resp.send(b"refreshed").ok();
+    builder.post(r"^/report/(.+)$", move |req, resp, captures| {
+        let name = percent_decode(&captures.unwrap()[1]);
+        let token = req.headers
+            .get::<Authorization<Bearer>>()
+            .map(|auth| auth.token.clone());
+        let token = token.as_ref().map(String::as_str);
+        // Don't read anything from whoever this is before checking.
+        if let Err(e) = server_.check_report(&name, token) {
+            return send_report_error(resp, e);
+        }
+        let too_large = req.headers
+            .get::<ContentLength>()
+            .map_or(false, |length| length.0 > MAX_REPORT_SIZE);
+        if too_large {
+            return send_error(resp,
+                              StatusCode::PayloadTooLarge,
+                              "Report too large");
+        }
+
+        let data = match serde_json::from_reader(req.take(MAX_REPORT_SIZE)) {
+            Ok(data) => data,
+            Err(e) => {
+                return send_error(resp,
+                                  StatusCode::BadRequest,
+                                  &format!("Invalid body: {}", e));
+            }
+        };
+        match server_.report(&name, token, data) {
+            Ok(()) => {
+                resp.send(b"ok").ok();
+            }
+            Err(e) => send_report_error(resp, e),
+        }
     });
 
     let server_ = server.clone();
-    let config_sync = config_sync_.clone();
-    builder.post(r"^/add/(.+)$", move |_, resp, captures| {
-        // Add a host
-        // Reads hostname -> creates a bare host, then start editing it.
-        let name = &captures.unwrap()[1];
-        // TODO: Check for valid hostname?
-        // Meh~
-        // We don't even set a authentication setting here.
-        match server_.with_conf(|conf| {
-            // Look for existing host
-            if conf.hosts.iter().any(|host| {
-                                         &host.address == name ||
-                                         &host.name == name
-                                     }) {
-                return Err(format!("Host {} already exists", name));
-            }
+    builder.post("^/refresh$", move |_, mut resp, _| {
+        // Don't wait for the hosts, tell the client where to look instead.
+        let id = server_.request_refresh();
+        *resp.status_mut() = StatusCode::Accepted;
+        resp.headers_mut().set(Location(format!("/refresh/{}", id)));
+        resp.send(&serde_json::to_vec(&json!({ "id": id })).unwrap()).ok();
+    });
 
-            conf.hosts.push(HostConfig {
-                                name: name.to_string(),
-                                address: name.to_string(),
-                                iface: "em1".to_string(),
-                                ..HostConfig::default()
-                            });
-            config_sync(conf);
-            Ok(())
-        }) {
-            Ok(_) => resp.send(b"added").ok(),
-            Err(msg) => resp.send(msg.as_bytes()).ok(),
-        };
+    let server_ = server.clone();
+    builder.post(r"^/refresh/(.+)$", move |_, mut resp, captures| {
+        let name = percent_decode(&captures.unwrap()[1]);
+        if server_.retry_host(&name) {
+            *resp.status_mut() = StatusCode::Accepted;
+            resp.send(b"retrying").ok();
+        } else {
+            send_error(resp, StatusCode::NotFound, "Host not found");
+        }
     });
 
     let server_ = server.clone();
-    let config_sync = config_sync_.clone();
-    builder.post("^/edit/(.+)$", move |req, resp, captures| {
-        // Edit a host
-        let hostname = &captures.unwrap()[1];
-        // Look for existing host
-        match server_.with_conf(|conf| {
-            match conf.hosts.iter_mut().find(|host| {
-                                                 &host.address == hostname
-                                             }) {
-                Some(host) => {
-                    // Do the actual edit.
-                    // Maybe directly a serialized HostConfig?
-                    // That we'd read from the request... cool!
-                    match serde_json::from_reader(req) {
-                        Ok(conf) => {
-                            *host = conf;
-                        }
-                        Err(e) => {
-                            return Err(format!("Invalid body: {:?}", e));
-                        }
-                    }
-                }
-                None => {
-                    // Return an error: host not found
-                    return Err("Host not found".to_string());
-                }
+    builder.get(r"^/refresh/(\d+)$", move |_, resp, captures| {
+        let status = captures.unwrap()[1]
+            .parse()
+            .ok()
+            .and_then(|id| server_.refresh_status(id));
+        match status {
+            Some(status) => {
+                resp.send(&serde_json::to_vec(&status).unwrap()).ok();
             }
-            config_sync(conf);
-            Ok(())
-        }) {
-            Ok(_) => resp.send(b"ok").ok(),
-            Err(msg) => resp.send(msg.as_bytes()).ok(),
-        };
+            None => send_error(resp, StatusCode::NotFound, "Unknown refresh"),
+        }
     });
 
+    api::register(&mut builder, &server, &config_sync);
+
     let server_ = server.clone();
     builder.post("^/stop$", move |_, resp, _| {
-        // Edit a host
+        // Answer first: once stopped, the process may exit at any time.
+        resp.send(b"stopping").ok();
+        // `serve` does the actual work.
         server_.stop();
-        resp.send(b"stopped").ok();
     });
 
-    let router = builder.finalize().unwrap();
+    let app = App {
+        router: builder.finalize().unwrap(),
+        server: server.clone(),
+    };
+
+    let address = (bind.as_str(), http.port);
+    let mut listening = match tls {
+        Some(tls) => {
+            println!("Now listening on https://{}:{}", bind, http.port);
+            hyper::Server::https(address, tls)
+                .unwrap()
+                .handle_threads(app, THREADS)
+                .unwrap()
+        }
+        None => {
+            println!("Now listening on http://{}:{}", bind, http.port);
+            hyper::Server::http(address)
+                .unwrap()
+                .handle_threads(app, THREADS)
+                .unwrap()
+        }
+    };
+    let redirect_port = http.tls.as_ref().and_then(|tls| tls.redirect_port);
+    let mut redirecting = redirect_port.map(|redirect_port| {
+        println!("Redirecting http://{}:{} to HTTPS", bind, redirect_port);
+        hyper::Server::http((bind.as_str(), redirect_port))
+            .unwrap()
+            .handle(Redirect { port: http.port })
+            .unwrap()
+    });
+
+    server.wait_for_stop();
+    println!("Stopping.");
+    // hyper 0.10 can't stop listening: `close` only keeps `Listening` from
+    // waiting for its threads when dropped. `App` turns requests away until
+    // the process exits, once the fetches are done.
+    listening.close().ok();
+    if let Some(ref mut redirecting) = redirecting {
+        redirecting.close().ok();
+    }
 
-    println!("Now listening on port :{}", port);
-    hyper::Server::http(("0.0.0.0", port)).unwrap().handle(router).unwrap();
+    let finished =
+        server.wait_for_fetches(Duration::from_secs(STOP_TIMEOUT));
+    if !finished {
+        println!("Aborting the fetches still in progress.");
+    }
+    // Wait for a config write in progress, if any.
+    drop(server.current_conf());
+    finished
 }
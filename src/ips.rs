@@ -1,37 +1,49 @@
 use std::str::FromStr;
 
+/// A range of IPv4 addresses, like `192.168.0.0/16`.
 pub struct IpBlock {
     netmask: u32,
     network: u32,
 }
 
-impl IpBlock {
-    fn parse(ip: &str) -> u32 {
-        ip.split('.')
-            .take(4)
-            .map(|n| u8::from_str(n).unwrap_or(0))
-            .enumerate()
-            .map(|(i, n)| (n as u32) << (8 * (3 - i as u32)))
-            .fold(0, |a, b| a | b)
+/// Parses a dotted IPv4 address.
+fn parse_ip(ip: &str) -> Option<u32> {
+    let octets: Option<Vec<u8>> =
+        ip.split('.').map(|n| u8::from_str(n).ok()).collect();
+    match octets {
+        Some(ref octets) if octets.len() == 4 => {
+            Some(octets.iter().fold(0, |a, &n| (a << 8) | n as u32))
+        }
+        _ => None,
     }
+}
+
+impl FromStr for IpBlock {
+    type Err = String;
 
-    pub fn new(range: &str) -> Self {
-        let tokens: Vec<_> = range.split('/').collect();
-        let ip = Self::parse(tokens[0]);
-        // println!("Net: {:b}", ip);
-        let bits = 32 - u8::from_str(tokens[1]).unwrap_or(32);
-        let netmask = (!0u32 >> bits) << bits;
-        // println!("netmask: {:b}", netmask);
+    /// Parses `address/prefix`, or a single address.
+    fn from_str(range: &str) -> Result<Self, String> {
+        let mut tokens = range.trim().splitn(2, '/');
+        let ip = tokens
+            .next()
+            .and_then(parse_ip)
+            .ok_or_else(|| format!("invalid address in `{}`", range))?;
+        let bits = match tokens.next().map(u8::from_str) {
+            Some(Ok(bits)) if bits <= 32 => bits,
+            Some(_) => return Err(format!("invalid prefix in `{}`", range)),
+            None => 32,
+        };
+        let netmask = if bits == 0 { 0 } else { !0u32 << (32 - bits) };
 
-        IpBlock {
+        Ok(IpBlock {
             netmask: netmask,
             network: ip & netmask,
-        }
+        })
     }
+}
 
+impl IpBlock {
     pub fn matches(&self, ip: &str) -> bool {
-        let ip = Self::parse(ip);
-        // println!("IP: {:b}", ip);
-        (ip & self.netmask) == self.network
+        parse_ip(ip).map_or(false, |ip| (ip & self.netmask) == self.network)
     }
 }
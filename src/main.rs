@@ -1,5 +1,6 @@
 #[macro_use]
 extern crate serde_derive;
+extern crate serde;
 
 #[macro_use]
 extern crate tera;
@@ -7,31 +8,56 @@ extern crate tera;
 #[macro_use]
 extern crate error_chain;
 
-extern crate clap;
+#[macro_use]
 extern crate serde_json;
+
+extern crate argon2;
+extern crate bcrypt;
+extern crate clap;
 extern crate toml;
+extern crate toml_edit;
 extern crate xdg;
 extern crate ssh2;
 extern crate rayon;
-extern crate crossbeam;
 extern crate hyper;
+extern crate libc;
 extern crate reroute;
+extern crate signal_hook;
 extern crate time;
+extern crate openssl;
 extern crate openssl_probe;
 
 mod errors {
     error_chain! {}
 }
 
+mod alerts;
+mod api;
+mod auth;
+mod collect;
 mod config;
 mod data;
 mod fetch;
+mod history;
+mod metrics;
+mod notifier;
+mod pool;
+mod push;
+mod scheduler;
 mod server;
+mod signals;
+mod watch;
 mod http;
 mod ips;
 mod slack;
+mod ssh;
+mod ssh_config;
+mod tls;
+mod tunnel;
+mod validate;
 
 use clap::{Arg, App};
+use error_chain::ChainedError;
 
 fn read_args<'a>() -> clap::ArgMatches<'a> {
     App::new("farmview")
@@ -57,20 +83,28 @@ fn main() {
     println!("Farmview v{}", env!("CARGO_PKG_VERSION"));
 
     println!("Using config file {:?}", config_path);
-    let config = match config::read_config(&config_path) {
-        Ok(config) => config,
-        Err(e) => {
-            println!("Error loading config: {:?}", e);
-            config::Config::default()
+    let config = if config_path.exists() {
+        match config::read_config(&config_path) {
+            Ok(config) => config,
+            Err(e) => {
+                // Don't go on with an empty config, which we'd then save.
+                print!("{}", e.display_chain());
+                std::process::exit(1);
+            }
         }
+    } else {
+        println!("Config file not found, starting empty.");
+        config::Config::default()
     };
 
     openssl_probe::init_ssl_cert_env_vars();
 
     // And off we go!
-    http::serve(config, move |config| {
-        config::write_config(&config_path, config).unwrap();
+    let clean = http::serve(config, config_path.clone(), move |config| {
+        config::write_config(&config_path, config)
     });
+    println!("Stopped.");
+    std::process::exit(if clean { 0 } else { 1 });
 }
 
 fn default_config_path() -> std::io::Result<std::path::PathBuf> {
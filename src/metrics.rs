@@ -0,0 +1,241 @@
+//! Prometheus text exposition of the collected data.
+
+use data::{Data, HostData};
+use pool::PoolStats;
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Gauges, grouped by metric name.
+struct Exposition {
+    // (name, help, samples)
+    families: Vec<(&'static str, &'static str, Vec<String>)>,
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Exposition {
+    fn new() -> Self {
+        Exposition { families: Vec::new() }
+    }
+
+    fn gauge(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        labels: &[(&str, &str)],
+        value: f64,
+    ) {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|&(k, v)| format!("{}=\"{}\"", k, escape(v)))
+            .collect();
+        let sample = if labels.is_empty() {
+            format!("{} {}", name, value)
+        } else {
+            format!("{}{{{}}} {}", name, labels.join(","), value)
+        };
+
+        if let Some(family) = self.families.iter_mut().find(|f| f.0 == name) {
+            family.2.push(sample);
+            return;
+        }
+        self.families.push((name, help, vec![sample]));
+    }
+
+    fn render(&self) -> String {
+        let mut result = String::new();
+        for &(name, help, ref samples) in &self.families {
+            writeln!(result, "# HELP {} {}", name, help).unwrap();
+            writeln!(result, "# TYPE {} gauge", name).unwrap();
+            for sample in samples {
+                writeln!(result, "{}", sample).unwrap();
+            }
+        }
+        result
+    }
+}
+
+fn host_metrics(out: &mut Exposition, host: &HostData) {
+    let location = host.location.as_ref().map(String::as_str).unwrap_or("");
+    let labels = [("host", &*host.name), ("location", location)];
+
+    out.gauge(
+        "farmview_host_up",
+        "Whether the last fetch for this host succeeded.",
+        &labels,
+        if host.state.is_ok() { 1.0 } else { 0.0 },
+    );
+
+    if let Some(nproc) = host.nproc {
+        out.gauge(
+            "farmview_cpus",
+            "Number of processors.",
+            &labels,
+            nproc as f64,
+        );
+    }
+
+    if let Some(uptime) = host.uptime {
+        for (period, load) in ["1m", "5m", "15m"].iter().zip(uptime.iter()) {
+            out.gauge(
+                "farmview_load",
+                "Load average.",
+                &[labels[0], labels[1], ("period", *period)],
+                *load as f64,
+            );
+        }
+    }
+
+    if let Some(ref memory) = host.memory {
+        out.gauge(
+            "farmview_memory_used_bytes",
+            "Used memory.",
+            &labels,
+            memory.used as f64,
+        );
+        out.gauge(
+            "farmview_memory_total_bytes",
+            "Total memory.",
+            &labels,
+            memory.total as f64,
+        );
+    }
+
+    for disk in &host.disks {
+        let labels = [
+            labels[0],
+            labels[1],
+            ("mountpoint", &*disk.mountpoint),
+            ("device", &*disk.name),
+        ];
+        if let Some(size) = disk.size {
+            out.gauge(
+                "farmview_disk_size_bytes",
+                "Disk size.",
+                &labels,
+                size as f64,
+            );
+        }
+        if let Some(used) = disk.used {
+            out.gauge(
+                "farmview_disk_used_bytes",
+                "Used disk space.",
+                &labels,
+                used as f64,
+            );
+        }
+        if let Some(available) = disk.available {
+            out.gauge(
+                "farmview_disk_available_bytes",
+                "Available disk space.",
+                &labels,
+                available as f64,
+            );
+        }
+
+        let attrs = match disk.attrs {
+            Some(ref attrs) => attrs,
+            None => continue,
+        };
+        let mut names: Vec<_> = attrs.keys().collect();
+        names.sort();
+        for name in names {
+            let attr = &attrs[name];
+            let labels =
+                [labels[0], labels[1], labels[3], ("attribute", &**name)];
+            if let Ok(value) = attr.value.trim().parse() {
+                out.gauge(
+                    "farmview_smart_value",
+                    "Normalized SMART attribute value.",
+                    &labels,
+                    value,
+                );
+            }
+            // Raw values sometimes have extra info, like "35 (Min/Max 20/45)"
+            let raw = attr.raw.split_whitespace().next().unwrap_or("");
+            if let Ok(raw) = raw.parse() {
+                out.gauge(
+                    "farmview_smart_raw",
+                    "Raw SMART attribute value.",
+                    &labels,
+                    raw,
+                );
+            }
+        }
+    }
+
+    if let Some(ref network) = host.network {
+        if let Some(rx) = network.rx {
+            out.gauge(
+                "farmview_network_receive_bytes_per_second",
+                "Incoming network traffic.",
+                &labels,
+                rx as f64,
+            );
+        }
+        if let Some(tx) = network.tx {
+            out.gauge(
+                "farmview_network_transmit_bytes_per_second",
+                "Outgoing network traffic.",
+                &labels,
+                tx as f64,
+            );
+        }
+    }
+
+    if let Some(ref power) = host.power {
+        out.gauge(
+            "farmview_power_current_amperes",
+            "Current drawn by the host.",
+            &labels,
+            power.current as f64,
+        );
+    }
+}
+
+/// Renders the data in the Prometheus text format.
+///
+/// `durations` is the time the last fetch of each host took.
+pub fn render(
+    data: &Data,
+    durations: &HashMap<String, Duration>,
+    pool: &PoolStats,
+) -> String {
+    let mut out = Exposition::new();
+
+    let connections = [
+        ("new", pool.connects),
+        ("reused", pool.reuses),
+        ("broken", pool.failures),
+    ];
+    for &(kind, count) in &connections {
+        out.gauge(
+            "farmview_ssh_connections",
+            "SSH connections since startup, by outcome.",
+            &[("kind", kind)],
+            count as f64,
+        );
+    }
+
+    for (host, duration) in durations {
+        out.gauge(
+            "farmview_fetch_duration_seconds",
+            "Time taken by the last fetch of the host.",
+            &[("host", host)],
+            duration.as_secs() as f64 + duration.subsec_nanos() as f64 * 1e-9,
+        );
+    }
+
+    for host in &data.hosts {
+        host_metrics(&mut out, host);
+    }
+
+    out.render()
+}
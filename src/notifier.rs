@@ -0,0 +1,488 @@
+//! Destinations for alert notifications.
+
+use alerts::Notification;
+use config::{Config, NotifierConfig};
+use slack::SlackNotifier;
+use tls::TlsClient;
+
+use std::error;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hyper;
+use hyper::header::ContentType;
+use hyper::net::HttpsConnector;
+use serde_json;
+
+pub type BoxedError = Box<error::Error + Send + Sync>;
+
+/// Something that can tell humans about alerts.
+pub trait Notifier: Send + Sync {
+    fn notify(&self, notification: &Notification) -> Result<(), BoxedError>;
+}
+
+/// Variables describing a notification.
+///
+/// Used in webhook templates and command environments.
+fn variables(notification: &Notification) -> Vec<(&'static str, String)> {
+    let alert = notification.alert();
+    vec![
+        ("status", notification.status().to_string()),
+        ("rule", alert.rule.name.clone()),
+        (
+            "severity",
+            alert.rule.severity.unwrap_or_default().as_str().to_string(),
+        ),
+        ("host", alert.host.clone()),
+        ("subject", alert.subject.clone()),
+        ("value", alert.value.to_string()),
+        ("threshold", alert.rule.threshold.to_string()),
+        ("message", notification.message()),
+    ]
+}
+
+/// Builds the notifiers described in the config.
+///
+/// The legacy `[slack]` section is available as a notifier named `slack`.
+pub fn from_config(config: &Config) -> Vec<(String, Box<Notifier>)> {
+    let mut result: Vec<(String, Box<Notifier>)> = Vec::new();
+
+    if let Some(ref slack) = config.slack {
+        result.push((
+            "slack".to_string(),
+            Box::new(SlackNotifier::new(&slack.hook, &slack.channel)),
+        ));
+    }
+
+    for notifier in &config.notifiers {
+        let boxed: Box<Notifier> = match *notifier {
+            NotifierConfig::Slack {
+                ref hook,
+                ref channel,
+                ..
+            } => Box::new(SlackNotifier::new(hook, channel)),
+            NotifierConfig::Webhook {
+                ref url, ref body, ..
+            } => Box::new(WebhookNotifier {
+                url: url.clone(),
+                body: body.clone(),
+            }),
+            NotifierConfig::Email {
+                ref server,
+                ref from,
+                ref to,
+                ..
+            } => Box::new(EmailNotifier {
+                server: server.clone(),
+                from: from.clone(),
+                to: to.clone(),
+            }),
+            NotifierConfig::Command {
+                ref command,
+                ref args,
+                ..
+            } => Box::new(CommandNotifier {
+                command: command.clone(),
+                args: args.clone().unwrap_or_default(),
+            }),
+        };
+        result.push((notifier.name().to_string(), boxed));
+    }
+
+    result
+}
+
+/// POSTs a JSON body to an URL, over HTTP or HTTPS.
+pub struct WebhookNotifier {
+    pub url: String,
+    // Template for the body, where `{{ name }}` is replaced with variables.
+    // Values are JSON-escaped, but not quoted.
+    pub body: Option<String>,
+}
+
+impl WebhookNotifier {
+    fn render(&self, notification: &Notification) -> String {
+        let variables = variables(notification);
+        let template = match self.body {
+            Some(ref template) => template,
+            None => {
+                let mut object: serde_json::Map<String, serde_json::Value> =
+                    variables
+                        .into_iter()
+                        .map(|(k, v)| (k.to_string(), v.into()))
+                        .collect();
+                // Numbers stay numbers in the default body.
+                let alert = notification.alert();
+                let threshold = alert.rule.threshold;
+                object.insert("value".to_string(), json!(alert.value));
+                object.insert("threshold".to_string(), json!(threshold));
+                return serde_json::to_string(&object).unwrap();
+            }
+        };
+
+        let mut body = template.clone();
+        for (key, value) in variables {
+            // to_string gives us a quoted string, drop the quotes.
+            let escaped = serde_json::to_string(&value).unwrap();
+            let escaped = &escaped[1..escaped.len() - 1];
+            body = body.replace(&format!("{{{{ {} }}}}", key), escaped);
+            body = body.replace(&format!("{{{{{}}}}}", key), escaped);
+        }
+        body
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), BoxedError> {
+        let body = self.render(notification);
+        let tls = TlsClient::new().map_err(|e| e.to_string())?;
+        let mut client =
+            hyper::Client::with_connector(HttpsConnector::new(tls));
+        client.set_read_timeout(Some(Duration::from_secs(15)));
+        client.set_write_timeout(Some(Duration::from_secs(15)));
+
+        let response = client
+            .post(&self.url)
+            .header(ContentType::json())
+            .body(&body[..])
+            .send()?;
+        if !response.status.is_success() {
+            return Err(format!("webhook returned {}", response.status).into());
+        }
+        Ok(())
+    }
+}
+
+/// Sends a mail through a SMTP server.
+///
+/// No authentication or encryption is done: this is meant for a local relay.
+pub struct EmailNotifier {
+    // host:port
+    pub server: String,
+    pub from: String,
+    pub to: Vec<String>,
+}
+
+/// Reads a (possibly multi-line) SMTP reply, and checks its code.
+fn smtp_expect<R: BufRead>(reader: &mut R, expected: u16) -> io::Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "SMTP server closed the connection",
+            ));
+        }
+        // Multi-line replies look like `250-...`, the last one `250 ...`
+        if line.len() > 3 && line.as_bytes()[3] == b'-' {
+            continue;
+        }
+        return match line.get(..3).and_then(|code| code.parse::<u16>().ok()) {
+            Some(code) if code == expected => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("unexpected SMTP reply: {}", line.trim()),
+            )),
+        };
+    }
+}
+
+fn smtp_command<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    command: &str,
+    expected: u16,
+) -> io::Result<()> {
+    write!(writer, "{}\r\n", command)?;
+    writer.flush()?;
+    smtp_expect(reader, expected)
+}
+
+impl EmailNotifier {
+    fn send(&self, subject: &str, body: &str) -> io::Result<()> {
+        let stream = TcpStream::connect(&*self.server)?;
+        stream.set_read_timeout(Some(Duration::from_secs(15)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(15)))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+
+        smtp_expect(&mut reader, 220)?;
+        smtp_command(&mut reader, &mut writer, "HELO farmview", 250)?;
+        let from = format!("MAIL FROM:<{}>", self.from);
+        smtp_command(&mut reader, &mut writer, &from, 250)?;
+        for to in &self.to {
+            let rcpt = format!("RCPT TO:<{}>", to);
+            smtp_command(&mut reader, &mut writer, &rcpt, 250)?;
+        }
+        smtp_command(&mut reader, &mut writer, "DATA", 354)?;
+
+        write!(writer, "From: {}\r\n", self.from)?;
+        write!(writer, "To: {}\r\n", self.to.join(", "))?;
+        write!(writer, "Subject: {}\r\n\r\n", subject)?;
+        for line in body.lines() {
+            // Lines starting with a dot need an extra one.
+            if line.starts_with('.') {
+                write!(writer, ".")?;
+            }
+            write!(writer, "{}\r\n", line)?;
+        }
+        smtp_command(&mut reader, &mut writer, ".", 250)?;
+        smtp_command(&mut reader, &mut writer, "QUIT", 221)
+    }
+}
+
+impl Notifier for EmailNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), BoxedError> {
+        let alert = notification.alert();
+        let subject = format!(
+            "[farmview] {} {} on {}",
+            alert.rule.name,
+            notification.status(),
+            alert.host
+        );
+        self.send(&subject, &notification.message())?;
+        Ok(())
+    }
+}
+
+/// Seconds a notification command may run before it is killed.
+const COMMAND_TIMEOUT: u64 = 30;
+
+/// Runs a local command, with the alert in `FARMVIEW_*` variables.
+pub struct CommandNotifier {
+    pub command: String,
+    pub args: Vec<String>,
+}
+
+impl Notifier for CommandNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), BoxedError> {
+        let mut command = Command::new(&self.command);
+        command.args(&self.args);
+        for (key, value) in variables(notification) {
+            command.env(format!("FARMVIEW_{}", key.to_uppercase()), value);
+        }
+
+        let mut child = command.spawn()?;
+        let deadline = Instant::now() + Duration::from_secs(COMMAND_TIMEOUT);
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                // It may have exited in the meantime, that's fine.
+                child.kill().ok();
+                child.wait()?;
+                return Err(format!(
+                    "{} did not exit after {} seconds, killed it",
+                    self.command, COMMAND_TIMEOUT
+                ).into());
+            }
+            thread::sleep(Duration::from_millis(100));
+        };
+        if !status.success() {
+            return Err(format!("{} exited with {}", self.command, status)
+                .into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alerts::Alert;
+    use config::{AlertRule, Metric};
+
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::process;
+    use std::thread;
+
+    fn notification() -> Notification {
+        Notification::Firing(Alert {
+            rule: AlertRule {
+                name: "disk_full".to_string(),
+                metric: Metric::DiskRatio,
+                threshold: 0.98,
+                ..AlertRule::default()
+            },
+            host: "node1".to_string(),
+            subject: "/home".to_string(),
+            value: 0.99,
+        })
+    }
+
+    #[test]
+    fn webhook_posts_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut head = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                head.push(line.trim().to_string());
+            }
+            let length = head
+                .iter()
+                .find(|line| {
+                    line.to_lowercase().starts_with("content-length:")
+                })
+                .map(|line| line[15..].trim().parse::<usize>().unwrap())
+                .unwrap();
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let mut stream = stream;
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            (head, body)
+        });
+
+        let notifier = WebhookNotifier {
+            url: url,
+            body: None,
+        };
+        notifier.notify(&notification()).unwrap();
+        let (head, body) = server.join().unwrap();
+
+        assert_eq!(head[0], "POST /hook HTTP/1.1");
+        assert!(head.contains(&"Content-Type: application/json".to_string()));
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "status": "firing",
+                "rule": "disk_full",
+                "severity": "warning",
+                "host": "node1",
+                "subject": "/home",
+                "value": 0.99,
+                "threshold": 0.98,
+                "message": notification().message(),
+            })
+        );
+    }
+
+    #[test]
+    fn email_speaks_smtp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server_addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut received = Vec::new();
+            let mut in_data = false;
+            writer.write_all(b"220 sink ready\r\n").unwrap();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let line = line.trim().to_string();
+                let reply: &[u8] = if in_data {
+                    if line == "." {
+                        in_data = false;
+                        b"250 queued\r\n"
+                    } else {
+                        b""
+                    }
+                } else if line.starts_with("HELO") {
+                    b"250-sink\r\n250 hello\r\n"
+                } else if line == "DATA" {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line == "QUIT" {
+                    b"221 bye\r\n"
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(reply).unwrap();
+                let quit = line == "QUIT" && !in_data;
+                received.push(line);
+                if quit {
+                    break;
+                }
+            }
+            received
+        });
+
+        let notifier = EmailNotifier {
+            server: server_addr,
+            from: "farmview@example.com".to_string(),
+            to: vec![
+                "alice@example.com".to_string(),
+                "bob@example.com".to_string(),
+            ],
+        };
+        notifier.notify(&notification()).unwrap();
+        let received = server.join().unwrap();
+
+        assert_eq!(
+            received,
+            vec![
+                "HELO farmview".to_string(),
+                "MAIL FROM:<farmview@example.com>".to_string(),
+                "RCPT TO:<alice@example.com>".to_string(),
+                "RCPT TO:<bob@example.com>".to_string(),
+                "DATA".to_string(),
+                "From: farmview@example.com".to_string(),
+                "To: alice@example.com, bob@example.com".to_string(),
+                "Subject: [farmview] disk_full firing on node1".to_string(),
+                "".to_string(),
+                notification().message(),
+                ".".to_string(),
+                "QUIT".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn command_gets_variables() {
+        let path = env::temp_dir()
+            .join(format!("farmview-notifier-test-{}", process::id()));
+        let notifier = CommandNotifier {
+            command: "sh".to_string(),
+            // The path is $0 for the script.
+            args: vec![
+                "-c".to_string(),
+                "env > \"$0\"".to_string(),
+                path.to_string_lossy().into_owned(),
+            ],
+        };
+        notifier.notify(&notification()).unwrap();
+        let output = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut variables: Vec<&str> = output
+            .lines()
+            .filter(|line| line.starts_with("FARMVIEW_"))
+            .collect();
+        variables.sort();
+        let message = format!("FARMVIEW_MESSAGE={}", notification().message());
+        assert_eq!(
+            variables,
+            vec![
+                "FARMVIEW_HOST=node1",
+                message.as_str(),
+                "FARMVIEW_RULE=disk_full",
+                "FARMVIEW_SEVERITY=warning",
+                "FARMVIEW_STATUS=firing",
+                "FARMVIEW_SUBJECT=/home",
+                "FARMVIEW_THRESHOLD=0.98",
+                "FARMVIEW_VALUE=0.99",
+            ]
+        );
+    }
+}
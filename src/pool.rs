@@ -0,0 +1,159 @@
+//! Authenticated SSH sessions, kept alive between refreshes.
+
+use config::{Config, HostConfig};
+use data::HostState;
+use fetch::FetchError;
+use ssh::connect;
+use tunnel::Tunnels;
+
+use std::collections::HashMap;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+
+use ssh2;
+
+/// Seconds between two keepalive messages.
+const KEEPALIVE_INTERVAL: u32 = 30;
+
+struct Connection {
+    // Needs to live as long as the session.
+    _tcp: TcpStream,
+    sess: ssh2::Session,
+    // Settings used to open this connection
+    fingerprint: String,
+}
+
+/// Connection reuse statistics.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct PoolStats {
+    // New connections, including reconnections
+    pub connects: u64,
+    // Times an existing connection was used
+    pub reuses: u64,
+    // Existing connections found broken
+    pub failures: u64,
+}
+
+type Slot = Arc<Mutex<Option<Connection>>>;
+
+/// One session per host, reused across refreshes.
+pub struct SessionPool {
+    // Jump hosts are kept alive too.
+    tunnels: Tunnels,
+    connections: Mutex<HashMap<String, Slot>>,
+    stats: Mutex<PoolStats>,
+}
+
+/// Everything that affects how we connect to this host.
+fn fingerprint(host: &HostConfig, config: &Config) -> String {
+    let host = config.resolve_host(host);
+    format!(
+        "{:?}{:?}{:?}{:?}",
+        host,
+        config.ssh,
+        config.default,
+        config.jump_chain(&host).ok()
+    )
+}
+
+impl SessionPool {
+    pub fn new() -> Self {
+        SessionPool {
+            tunnels: Tunnels::new(),
+            connections: Mutex::new(HashMap::new()),
+            stats: Mutex::new(PoolStats::default()),
+        }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        *self.stats.lock().unwrap()
+    }
+
+    fn slot(&self, host: &HostConfig) -> Slot {
+        self.connections
+            .lock()
+            .unwrap()
+            .entry(host.name.clone())
+            .or_insert_with(|| Arc::new(Mutex::new(None)))
+            .clone()
+    }
+
+    /// Forgets the hosts that are not in the config anymore.
+    pub fn prune(&self, config: &Config) {
+        self.connections
+            .lock()
+            .unwrap()
+            .retain(|name, _| config.hosts.iter().any(|h| &h.name == name));
+    }
+
+    /// Runs `f` with a session to this host.
+    ///
+    /// A live session is reused if possible. If `f` fails on a reused
+    /// session because the host is unreachable, it is retried once on a
+    /// fresh one: the connection may have died since last time.
+    pub fn with_session<T, F>(
+        &self,
+        host: &HostConfig,
+        config: &Config,
+        mut f: F,
+    ) -> Result<T, FetchError>
+    where
+        F: FnMut(&ssh2::Session) -> Result<T, FetchError>,
+    {
+        let slot = self.slot(host);
+        // Only one user per host at a time.
+        let mut connection = slot.lock().unwrap();
+        let fingerprint = fingerprint(host, config);
+
+        // Drop the connection if the settings changed.
+        let outdated = match *connection {
+            Some(ref c) => c.fingerprint != fingerprint,
+            None => false,
+        };
+        if outdated {
+            *connection = None;
+        }
+
+        // Or if it doesn't answer.
+        let healthy = match *connection {
+            Some(ref c) => c.sess.keepalive_send().is_ok(),
+            None => true,
+        };
+        if !healthy {
+            self.stats.lock().unwrap().failures += 1;
+            *connection = None;
+        }
+
+        if connection.is_some() {
+            self.stats.lock().unwrap().reuses += 1;
+            let result = f(&connection.as_ref().unwrap().sess);
+            let state = result.as_ref().err().map(|e| e.state);
+            if state == Some(HostState::TimedOut) {
+                // Don't wait on it again next time.
+                *connection = None;
+            }
+            // Script errors would fail the same way on a new connection.
+            if state != Some(HostState::Unreachable) {
+                return result;
+            }
+            // Maybe the connection died since last time.
+            self.stats.lock().unwrap().failures += 1;
+            *connection = None;
+        }
+
+        let (tcp, sess) = connect(host, config, &self.tunnels)?;
+        sess.set_keepalive(true, KEEPALIVE_INTERVAL);
+        self.stats.lock().unwrap().connects += 1;
+
+        let result = f(&sess);
+        // Don't keep a connection that just failed.
+        if result.is_ok() {
+            *connection = Some(Connection {
+                _tcp: tcp,
+                sess: sess,
+                fingerprint: fingerprint,
+            });
+        }
+        result
+    }
+}
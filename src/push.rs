@@ -0,0 +1,81 @@
+//! Data pushed by hosts we cannot reach.
+
+use config::Config;
+use data::{HostData, HostState};
+use fetch::FetchError;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use time;
+
+struct Report {
+    data: HostData,
+    received: Instant,
+}
+
+/// Latest report from each host using the `push` transport.
+pub struct Reports {
+    latest: Mutex<HashMap<String, Report>>,
+}
+
+/// Compares tokens without leaking where they differ.
+pub fn token_matches(expected: &str, given: &str) -> bool {
+    let (expected, given) = (expected.as_bytes(), given.as_bytes());
+    if expected.len() != given.len() {
+        return false;
+    }
+    expected
+        .iter()
+        .zip(given)
+        .fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+impl Reports {
+    pub fn new() -> Self {
+        Reports {
+            latest: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Stores a report from this host.
+    pub fn store(&self, name: &str, mut data: HostData) {
+        data.last_success = Some(format!("{}", time::now().rfc3339()));
+        let report = Report {
+            data: data,
+            received: Instant::now(),
+        };
+        self.latest.lock().unwrap().insert(name.to_string(), report);
+    }
+
+    /// Returns the last report from this host.
+    ///
+    /// Fails if it is older than `stale_after`.
+    pub fn get(
+        &self,
+        name: &str,
+        stale_after: Duration,
+    ) -> Result<HostData, FetchError> {
+        let latest = self.latest.lock().unwrap();
+        let report = latest.get(name).ok_or_else(|| {
+            FetchError::new(HostState::Stale, "no report received yet")
+        })?;
+        let age = report.received.elapsed();
+        if age > stale_after {
+            return Err(FetchError::new(
+                HostState::Stale,
+                format!("no report for {} seconds", age.as_secs()),
+            ));
+        }
+        Ok(report.data.clone())
+    }
+
+    /// Forgets the hosts that are not in the config anymore.
+    pub fn prune(&self, config: &Config) {
+        self.latest
+            .lock()
+            .unwrap()
+            .retain(|name, _| config.hosts.iter().any(|h| &h.name == name));
+    }
+}
@@ -0,0 +1,267 @@
+//! Decides when each host is fetched.
+
+use config::{Config, HostConfig};
+
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+/// Seconds between two fetches when nothing is configured.
+const DEFAULT_DELAY: u64 = 30;
+
+/// Maximum seconds between two attempts on a failing host, by default.
+const DEFAULT_MAX_BACKOFF: u64 = 600;
+
+/// Returns the delay between two fetches of this host.
+///
+/// The host setting wins over its location's, which wins over the global one.
+pub fn refresh_delay(host: &HostConfig, config: &Config) -> Duration {
+    let location = host.location.as_ref().and_then(|location| {
+        config.locations.iter().find(|l| &l.name == location)
+    });
+    let seconds = host.refresh_delay
+        .or_else(|| location.and_then(|l| l.refresh_delay))
+        .or(config.refresh_delay)
+        .unwrap_or(DEFAULT_DELAY);
+    Duration::from_secs(seconds)
+}
+
+/// A random duration up to a tenth of `delay`.
+///
+/// Spreads the fetches, so hosts with the same delay don't all connect at
+/// the same time.
+fn jitter(delay: Duration) -> Duration {
+    let max = delay.as_secs() * 1000 / 10;
+    if max == 0 {
+        return Duration::from_secs(0);
+    }
+    // Each RandomState has random keys, good enough for this.
+    let random = RandomState::new().build_hasher().finish();
+    Duration::from_millis(random % max)
+}
+
+struct Entry {
+    due: Instant,
+    // A fetch is in progress
+    running: bool,
+    // Fetch again as soon as the current fetch is done
+    again: bool,
+    // Consecutive failed fetches
+    failures: u32,
+}
+
+/// Tracks when each host is next due.
+pub struct Scheduler {
+    hosts: HashMap<String, Entry>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler {
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// Adds the new hosts of the config, and forgets the removed ones.
+    ///
+    /// New hosts are due soon, within a tenth of their delay.
+    pub fn sync(&mut self, config: &Config) {
+        self.hosts
+            .retain(|name, _| config.hosts.iter().any(|h| &h.name == name));
+        let now = Instant::now();
+        for host in &config.hosts {
+            self.hosts.entry(host.name.clone()).or_insert_with(|| Entry {
+                due: now + jitter(refresh_delay(host, config)),
+                running: false,
+                again: false,
+                failures: 0,
+            });
+        }
+    }
+
+    /// Returns the hosts due by `now`, and marks them as running.
+    pub fn take_due(&mut self, now: Instant) -> Vec<String> {
+        let mut result = Vec::new();
+        for (name, entry) in &mut self.hosts {
+            if !entry.running && entry.due <= now {
+                entry.running = true;
+                result.push(name.clone());
+            }
+        }
+        result
+    }
+
+    /// Makes every host due now.
+    ///
+    /// Hosts being fetched are fetched again once done, so the data is
+    /// fresher than the request.
+    pub fn expire_all(&mut self) {
+        let now = Instant::now();
+        for entry in self.hosts.values_mut() {
+            entry.due = now;
+            entry.again = entry.running;
+        }
+    }
+
+    /// Makes this host due now, even if it is backing off.
+    ///
+    /// Returns `false` if the host is unknown.
+    pub fn retry_now(&mut self, name: &str) -> bool {
+        match self.hosts.get_mut(name) {
+            Some(entry) => {
+                entry.due = Instant::now();
+                entry.again = entry.running;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Schedules the next fetch of this host, depending on how this one went.
+    ///
+    /// Each consecutive failure doubles the delay, up to the `max_backoff`
+    /// of the config. Returns the time until the next fetch.
+    pub fn finish(
+        &mut self,
+        host: &HostConfig,
+        config: &Config,
+        success: bool,
+    ) -> Duration {
+        let entry = match self.hosts.get_mut(&host.name) {
+            Some(entry) => entry,
+            None => return Duration::from_secs(0),
+        };
+        entry.running = false;
+        entry.failures = if success { 0 } else { entry.failures + 1 };
+
+        let wait = if entry.again {
+            Duration::from_secs(0)
+        } else {
+            let delay = refresh_delay(host, config);
+            let max = Duration::from_secs(
+                config.max_backoff.unwrap_or(DEFAULT_MAX_BACKOFF),
+            );
+            // The first failure is retried after the usual delay.
+            let factor = 1 << entry.failures.saturating_sub(1).min(16);
+            let delay = if entry.failures > 0 {
+                (delay * factor).min(max.max(delay))
+            } else {
+                delay
+            };
+            delay + jitter(delay)
+        };
+        entry.again = false;
+        entry.due = Instant::now() + wait;
+        wait
+    }
+
+    /// When the next host is due, if any.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.hosts
+            .values()
+            .filter(|entry| !entry.running)
+            .map(|entry| entry.due)
+            .min()
+    }
+}
+
+/// Progress of a refresh requested through the API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum RefreshStatus {
+    // Waiting for the refresh thread
+    Queued,
+    // Some hosts were not fetched yet
+    Running { remaining: usize },
+    Done,
+}
+
+struct Refresh {
+    started: Instant,
+    // Hosts not fetched since the refresh started
+    remaining: HashSet<String>,
+}
+
+/// Refreshes requested through the API, and their progress.
+pub struct Refreshes {
+    next_id: u64,
+    // Requests waiting for the refresh thread are merged into this one.
+    queued: Option<u64>,
+    running: HashMap<u64, Refresh>,
+}
+
+impl Refreshes {
+    pub fn new() -> Self {
+        Refreshes {
+            next_id: 1,
+            queued: None,
+            running: HashMap::new(),
+        }
+    }
+
+    /// Returns the id of the queued refresh, and whether it was just queued.
+    pub fn request(&mut self) -> (u64, bool) {
+        if let Some(id) = self.queued {
+            return (id, false);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queued = Some(id);
+        (id, true)
+    }
+
+    /// Starts the queued refresh, if any, for all hosts.
+    pub fn start(&mut self, config: &Config) {
+        if let Some(id) = self.queued.take() {
+            let remaining =
+                config.hosts.iter().map(|h| h.name.clone()).collect();
+            let refresh = Refresh {
+                started: Instant::now(),
+                remaining: remaining,
+            };
+            self.running.insert(id, refresh);
+            self.cleanup();
+        }
+    }
+
+    /// Records that a fetch of this host, started at `start`, finished.
+    pub fn finished(&mut self, name: &str, start: Instant) {
+        for refresh in self.running.values_mut() {
+            if start >= refresh.started {
+                refresh.remaining.remove(name);
+            }
+        }
+        self.cleanup();
+    }
+
+    /// Forgets the hosts that are not in the config anymore.
+    pub fn prune(&mut self, config: &Config) {
+        for refresh in self.running.values_mut() {
+            refresh
+                .remaining
+                .retain(|name| config.hosts.iter().any(|h| &h.name == name));
+        }
+        self.cleanup();
+    }
+
+    fn cleanup(&mut self) {
+        self.running.retain(|_, refresh| !refresh.remaining.is_empty());
+    }
+
+    pub fn status(&self, id: u64) -> Option<RefreshStatus> {
+        if self.queued == Some(id) {
+            return Some(RefreshStatus::Queued);
+        }
+        if let Some(refresh) = self.running.get(&id) {
+            return Some(RefreshStatus::Running {
+                remaining: refresh.remaining.len(),
+            });
+        }
+        if id > 0 && id < self.next_id {
+            Some(RefreshStatus::Done)
+        } else {
+            None
+        }
+    }
+}
@@ -1,19 +1,80 @@
-use config::Config;
-use fetch::{fetch_data, prepare_hosts};
-use data::Data;
+use alerts::{default_rules, AlertEngine, Notification};
+use config::{read_config, Config, Transport};
+use fetch::{fetch_host, prepare_hosts};
+use data::{AlertData, Data, HostData};
+use history::History;
+use notifier::{self, Notifier};
+use pool::{PoolStats, SessionPool};
+use push::{token_matches, Reports};
+use scheduler::{RefreshStatus, Refreshes, Scheduler};
 
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use std::time::Instant;
 use std::thread;
 use std::time::Duration;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, RwLock,
+                RwLockReadGuard};
+
+use error_chain::ChainedError;
+use time;
+
+/// Sent to the refresh thread.
+enum Command {
+    // Fetch every host now
+    Refresh,
+    // Prepare these hosts again, and fetch them
+    ConfigChanged(Vec<String>),
+    // A fetch finished, the next due host may have changed
+    Wake,
+    Stop,
+}
+
+/// Sent to the notification thread.
+enum Outgoing {
+    // The notifiers changed in the config
+    Notifiers(Vec<(String, Box<Notifier>)>),
+    Send(Vec<Notification>),
+}
+
+/// Something the dashboard shows changed.
+#[derive(Debug, Clone)]
+pub enum Event {
+    // Fresh data for a host
+    Host(HostData),
+    // A host was removed from the config
+    Removed(String),
+    // The alerts firing, when they changed
+    Alerts(Vec<AlertData>),
+}
 
 pub struct Server {
     config: Mutex<Config>,
     data: RwLock<Data>,
-    running: AtomicBool,
-    warnings_sent: Mutex<HashMap<(String, String), Instant>>,
+    alerts: Mutex<AlertEngine>,
+    // Talks to the notification thread
+    notifications: Mutex<mpsc::Sender<Outgoing>>,
+    history: History,
+    // Talks to the refresh thread
+    commands: Mutex<mpsc::Sender<Command>>,
+    // When each host is next fetched
+    scheduler: Mutex<Scheduler>,
+    // Refreshes requested through the API
+    refreshes: Mutex<Refreshes>,
+    // How long the last fetch of each host took
+    durations: Mutex<HashMap<String, Duration>>,
+    // SSH sessions, kept between refreshes
+    pool: SessionPool,
+    // Data pushed by hosts
+    reports: Reports,
+    // Set once, when the server is asked to stop
+    stopping: Mutex<bool>,
+    stopped: Condvar,
+    // Fetches in progress
+    in_flight: Mutex<usize>,
+    idle: Condvar,
+    // Where to send events
+    subscribers: Mutex<Vec<mpsc::Sender<Event>>>,
 }
 
 /// Handles concurrent access to config/data.
@@ -22,56 +83,107 @@ pub struct Server {
 ///
 /// This makes this class safe to put inside an `Arc`.
 impl Server {
-    /// Creates a new Server, and starts a separate thread to periodically refresh the data.
+    /// Creates a new Server, and starts a separate thread to fetch each host
+    /// when it is due.
     pub fn new(config: Config) -> Arc<Self> {
-        // Prepare the hosts the first time
+        let (sender, commands) = mpsc::channel();
+        let (notifications, outgoing) = mpsc::channel();
+        // Notifiers can be slow, they shouldn't hold fetches back.
+        let notifiers = notifier::from_config(&config);
+        thread::spawn(move || send_notifications(notifiers, outgoing));
 
         let result = Arc::new(Server {
             config: Mutex::new(config),
             data: RwLock::new(Data::default()),
-            // Indicate that the refresh thread is running
-            running: AtomicBool::new(true),
-            warnings_sent: Mutex::new(HashMap::new()),
+            alerts: Mutex::new(AlertEngine::new()),
+            notifications: Mutex::new(notifications),
+            history: History::new(),
+            commands: Mutex::new(sender),
+            scheduler: Mutex::new(Scheduler::new()),
+            refreshes: Mutex::new(Refreshes::new()),
+            durations: Mutex::new(HashMap::new()),
+            pool: SessionPool::new(),
+            reports: Reports::new(),
+            stopping: Mutex::new(false),
+            stopped: Condvar::new(),
+            in_flight: Mutex::new(0),
+            idle: Condvar::new(),
+            subscribers: Mutex::new(Vec::new()),
         });
 
         // Spawn a refresh thread.
         let cloned = result.clone();
         thread::spawn(move || {
-            // Refresh once immediately
-            prepare_hosts(&cloned.current_conf());
-            cloned.refresh();
-
-            // TODO: select! on a refresh channel and a timer.
-            while cloned.running.load(Ordering::Relaxed) {
-                thread::sleep(Duration::from_secs(cloned.refresh_delay()));
-                cloned.refresh();
-            }
+            // Prepare the hosts the first time
+            let conf = cloned.current_conf().clone();
+            let hosts: Vec<_> = conf.hosts.iter().collect();
+            prepare_hosts(&hosts, &conf, &cloned.pool);
+            schedule(cloned, commands);
         });
 
         result
     }
 
-    fn refresh_delay(&self) -> u64 {
-        self.current_conf().refresh_delay.unwrap_or(30)
-    }
-
-    /// Update the configuration, and refresh everything.
+    /// Update the configuration.
     ///
-    /// You should run this async (in a thread::spawn for instance).
+    /// If `update` fails, the configuration is left untouched. Otherwise,
+    /// the refresh thread then prepares and fetches the new or changed hosts
+    /// again.
     pub fn with_conf<E, F>(&self, update: F) -> Result<(), E>
     where
         F: FnOnce(&mut Config) -> Result<(), E>,
     {
-        {
+        let changed = {
             let mut config = self.current_conf();
-            update(&mut config)?;
-            prepare_hosts(&config);
+            let mut updated = config.clone();
+            update(&mut updated)?;
+            let changed = updated.changed_hosts(&config);
+            if updated.slack != config.slack ||
+                updated.notifiers != config.notifiers
+            {
+                let notifiers = notifier::from_config(&updated);
+                self.notify(Outgoing::Notifiers(notifiers));
+            }
+            *config = updated;
+            changed
+        };
+        self.send(Command::ConfigChanged(changed));
+        Ok(())
+    }
+
+    /// Reads the config file again, and applies it.
+    ///
+    /// If the file is invalid, the current config is kept.
+    pub fn reload(&self, path: &Path) {
+        let fresh = match read_config(path) {
+            Ok(fresh) => fresh,
+            Err(e) => {
+                print!(
+                    "Could not reload {:?}, keeping the current config.\n{}",
+                    path,
+                    e.display_chain()
+                );
+                return;
+            }
+        };
+        if *self.current_conf() == fresh {
+            return;
         }
+        println!("Reloading config from {:?}", path);
+        self.with_conf(|config| -> Result<(), ()> {
+            *config = fresh;
+            Ok(())
+        }).ok();
+    }
 
-        // And then just refresh
-        // TODO: send a signal to the refresh thread instead?
-        self.refresh();
-        Ok(())
+    fn notify(&self, outgoing: Outgoing) {
+        // Fails if the notification thread died, nothing to do about it.
+        self.notifications.lock().unwrap().send(outgoing).ok();
+    }
+
+    fn send(&self, command: Command) {
+        // Fails if the refresh thread stopped, then nobody cares.
+        self.commands.lock().unwrap().send(command).ok();
     }
 
     pub fn current_conf(&self) -> MutexGuard<Config> {
@@ -83,61 +195,366 @@ impl Server {
         self.data.read().unwrap()
     }
 
-    pub fn refresh(&self) {
-        // Don't lock while we're fetching.
+    /// Returns the events from now on, until the server stops.
+    pub fn subscribe(&self) -> mpsc::Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Number of subscribers still listening.
+    pub fn subscribers(&self) -> usize {
+        self.subscribers.lock().unwrap().len()
+    }
+
+    /// Sends an event to every subscriber, forgetting those who left.
+    fn publish(&self, event: Event) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// How long the last fetch of each host took.
+    pub fn durations(&self) -> HashMap<String, Duration> {
+        self.durations.lock().unwrap().clone()
+    }
+
+    /// SSH connection reuse statistics.
+    pub fn pool_stats(&self) -> PoolStats {
+        self.pool.stats()
+    }
+
+    /// Asks for every host to be fetched now.
+    ///
+    /// Returns an id to follow the refresh with `refresh_status`. Requests
+    /// made before the refresh thread picks them up share the same id.
+    pub fn request_refresh(&self) -> u64 {
+        let (id, queued) = self.refreshes.lock().unwrap().request();
+        if queued {
+            self.send(Command::Refresh);
+        }
+        id
+    }
+
+    pub fn refresh_status(&self, id: u64) -> Option<RefreshStatus> {
+        self.refreshes.lock().unwrap().status(id)
+    }
+
+    /// Makes every host due now, for the given refresh if any.
+    fn start_refresh(&self, conf: &Config) {
         println!("Refreshing.");
-        let start = Instant::now();
+        self.scheduler.lock().unwrap().expire_all();
+        self.refreshes.lock().unwrap().start(conf);
+    }
+
+    /// Fetches a host, which must be marked as running in the scheduler,
+    /// and counted in `in_flight`.
+    fn refresh_host(&self, name: &str) {
         let conf = self.current_conf().clone();
-        let fresh = fetch_data(&conf);
-        let mut warnings_sent = self.warnings_sent.lock().unwrap();
-        if let Some(ref slack) = conf.slack {
-            for host in &fresh.hosts {
-                for disk in &host.disks {
-                    if let (Some(used), Some(size)) = (disk.used, disk.size) {
-                        if (100 * used) > (size * 98) {
-                            let hostname = host.hostname
-                                .as_ref()
-                                .map(String::as_str)
-                                .unwrap_or("");
-                            let key =
-                                (hostname.to_string(), disk.name.clone());
-                            if let Some(last) = warnings_sent.get(&key) {
-                                if last.elapsed()
-                                    < Duration::from_secs(60 * 30)
-                                {
-                                    continue;
-                                }
-                            }
-                            warnings_sent.insert(key, Instant::now());
-                            if let Err(err) = ::slack::send_alert(
-                                &slack.hook,
-                                &slack.channel,
-                                hostname,
-                                &disk.name,
-                                &disk.mountpoint,
-                                (100 * used) / size,
-                            ) {
-                                println!(
-                                    "Error sending slack notification: {}",
-                                    err
-                                );
-                            }
-                        }
-                    }
-                }
-            }
+        let host = match conf.hosts.iter().find(|h| h.name == name) {
+            Some(host) => host,
+            // Removed in the meantime
+            None => return,
+        };
+
+        let start = Instant::now();
+        let mut fresh = fetch_host(host, &conf, &self.pool, &self.reports);
+        self.durations
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), start.elapsed());
+
+        let wait = self.scheduler.lock().unwrap().finish(
+            host,
+            &conf,
+            fresh.state.is_ok(),
+        );
+        if !fresh.state.is_ok() {
+            let next = time::now() + time::Duration::from_std(wait).unwrap();
+            fresh.next_retry = Some(format!("{}", next.rfc3339()));
         }
+        self.update_host(&conf, fresh);
 
-        let mut data = self.data.write().unwrap();
-        *data = fresh;
-        println!("Refreshed ({:?})", start.elapsed());
+        self.refreshes.lock().unwrap().finished(name, start);
+        self.send(Command::Wake);
     }
 
-    /// Stops the refresh thread.
+    /// Fetches this host now, even if it is backing off.
     ///
+    /// Returns `false` if the host is unknown.
+    pub fn retry_host(&self, name: &str) -> bool {
+        let known = self.scheduler.lock().unwrap().retry_now(name);
+        if known {
+            self.send(Command::Wake);
+        }
+        known
+    }
+
+    /// Replaces the data of a host with fresh data.
+    fn update_host(&self, conf: &Config, mut fresh: HostData) {
+        if !fresh.state.is_ok() {
+            fresh.last_success = self.latest_data()
+                .hosts
+                .iter()
+                .find(|old| old.name == fresh.name)
+                .and_then(|old| old.last_success.clone());
+        }
+        if let Some(ref history) = conf.history {
+            let data = Data {
+                hosts: vec![fresh.clone()],
+                ..Data::default()
+            };
+            self.history.record(history, &data);
+        }
+        let alerts = self.check_alerts(conf, &fresh);
+
+        let (removed, alerts_changed) = {
+            let mut data = self.data.write().unwrap();
+            let removed: Vec<String> = data.hosts
+                .iter()
+                .filter(|host| !conf.hosts.iter().any(|h| h.name == host.name))
+                .map(|host| host.name.clone())
+                .collect();
+            data.hosts.retain(|host| {
+                host.name != fresh.name && !removed.contains(&host.name)
+            });
+            data.hosts.push(fresh.clone());
+            data.hosts.sort_by(|a, b| {
+                (&a.location, &a.name).cmp(&(&b.location, &b.name))
+            });
+            let alerts_changed = data.alerts != alerts;
+            data.alerts = alerts.clone();
+            data.update_time = format!("{}", time::now().rfc3339());
+            (removed, alerts_changed)
+        };
+
+        for name in removed {
+            self.publish(Event::Removed(name));
+        }
+        self.publish(Event::Host(fresh));
+        if alerts_changed {
+            self.publish(Event::Alerts(alerts));
+        }
+    }
+
+    /// Checks that this host pushes its data, with this token.
+    pub fn check_report(
+        &self,
+        name: &str,
+        token: Option<&str>,
+    ) -> Result<(), ReportError> {
+        let conf = self.current_conf();
+        let host = conf.hosts
+            .iter()
+            .find(|h| h.name == name)
+            .ok_or(ReportError::UnknownHost)?;
+        if host.transport != Some(Transport::Push) {
+            return Err(ReportError::NotPushing);
+        }
+        let valid = match (host.token.as_ref(), token) {
+            (Some(expected), Some(given)) => token_matches(expected, given),
+            _ => false,
+        };
+        if !valid {
+            return Err(ReportError::BadToken);
+        }
+        Ok(())
+    }
+
+    /// Stores data pushed by a host, after checking its token.
+    ///
+    /// The host is updated right after, by the refresh thread.
+    pub fn report(
+        &self,
+        name: &str,
+        token: Option<&str>,
+        data: HostData,
+    ) -> Result<(), ReportError> {
+        // The config may have changed while the data was read.
+        self.check_report(name, token)?;
+        self.reports.store(name, data);
+        // The refresh thread applies it, the host doesn't have to wait.
+        self.retry_host(name);
+        Ok(())
+    }
+
+    /// Evaluates the alert rules on a host, and queues notifications.
+    ///
+    /// Returns the alerts currently firing, for all hosts.
+    fn check_alerts(&self, conf: &Config, fresh: &HostData) -> Vec<AlertData> {
+        let defaults;
+        let rules = if conf.alerts.is_empty() {
+            defaults = default_rules();
+            &defaults
+        } else {
+            &conf.alerts
+        };
+
+        let (notifications, firing) = {
+            let mut engine = self.alerts.lock().unwrap();
+            engine.prune(conf, rules);
+            (engine.evaluate(rules, fresh), engine.firing())
+        };
+
+        if !notifications.is_empty() {
+            self.notify(Outgoing::Send(notifications));
+        }
+        firing
+    }
+
+    /// Stops the refresh thread, and wakes up `wait_for_stop`.
+    ///
+    /// Returns `false` if the server was already stopping.
     /// This is called automatically on drop.
-    pub fn stop(&self) {
-        self.running.store(false, Ordering::Relaxed);
+    pub fn stop(&self) -> bool {
+        let mut stopping = self.stopping.lock().unwrap();
+        if *stopping {
+            return false;
+        }
+        *stopping = true;
+        self.send(Command::Stop);
+        // Ends the event streams.
+        self.subscribers.lock().unwrap().clear();
+        self.stopped.notify_all();
+        true
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        *self.stopping.lock().unwrap()
+    }
+
+    /// Blocks until `stop` is called.
+    pub fn wait_for_stop(&self) {
+        let mut stopping = self.stopping.lock().unwrap();
+        while !*stopping {
+            stopping = self.stopped.wait(stopping).unwrap();
+        }
+    }
+
+    /// Waits for the fetches in progress, for up to `timeout`.
+    ///
+    /// Returns `false` if some are still running.
+    pub fn wait_for_fetches(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut in_flight = self.in_flight.lock().unwrap();
+        while *in_flight > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            in_flight = self.idle.wait_timeout(in_flight, deadline - now)
+                .unwrap()
+                .0;
+        }
+        true
+    }
+}
+
+/// Why a report was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportError {
+    UnknownHost,
+    // The host doesn't use the `push` transport
+    NotPushing,
+    // Missing or wrong token
+    BadToken,
+}
+
+/// Sends notifications in order, until the server is dropped.
+fn send_notifications(
+    mut notifiers: Vec<(String, Box<Notifier>)>,
+    outgoing: mpsc::Receiver<Outgoing>,
+) {
+    for message in outgoing {
+        let notifications = match message {
+            Outgoing::Notifiers(fresh) => {
+                notifiers = fresh;
+                continue;
+            }
+            Outgoing::Send(notifications) => notifications,
+        };
+        for notification in notifications {
+            let targets = notification.alert().rule.notify.as_ref();
+            for &(ref name, ref notifier) in &notifiers {
+                if !targets.map_or(true, |targets| targets.contains(name)) {
+                    continue;
+                }
+                if let Err(err) = notifier.notify(&notification) {
+                    println!("Error notifying {}: {}", name, err);
+                }
+            }
+        }
+    }
+}
+
+/// Runs the fetches as hosts become due, until the server stops.
+fn schedule(server: Arc<Server>, commands: mpsc::Receiver<Command>) {
+    loop {
+        let conf = server.current_conf().clone();
+        server.pool.prune(&conf);
+        server.reports.prune(&conf);
+        server.refreshes.lock().unwrap().prune(&conf);
+
+        let (due, next) = {
+            let mut scheduler = server.scheduler.lock().unwrap();
+            scheduler.sync(&conf);
+            let due = scheduler.take_due(Instant::now());
+            (due, scheduler.next_due())
+        };
+        for name in due {
+            // Counted before the thread starts, for `wait_for_fetches`.
+            *server.in_flight.lock().unwrap() += 1;
+            let server = server.clone();
+            thread::spawn(move || {
+                server.refresh_host(&name);
+                *server.in_flight.lock().unwrap() -= 1;
+                server.idle.notify_all();
+            });
+        }
+
+        // Sleep until the next host is due, or something happens.
+        let now = Instant::now();
+        let wait = match next {
+            Some(next) if next > now => next - now,
+            Some(_) => continue,
+            None => Duration::from_secs(60),
+        };
+        let first = match commands.recv_timeout(wait) {
+            Ok(command) => command,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        };
+
+        // Handle everything that piled up at once.
+        let mut refresh = false;
+        let mut changed = HashSet::new();
+        for command in Some(first).into_iter().chain(commands.try_iter()) {
+            match command {
+                Command::Refresh => refresh = true,
+                Command::ConfigChanged(names) => changed.extend(names),
+                Command::Wake => (),
+                Command::Stop => return,
+            }
+        }
+        if !changed.is_empty() {
+            let conf = server.current_conf().clone();
+            let hosts: Vec<_> = conf.hosts
+                .iter()
+                .filter(|host| changed.contains(&host.name))
+                .collect();
+            prepare_hosts(&hosts, &conf, &server.pool);
+
+            let mut scheduler = server.scheduler.lock().unwrap();
+            scheduler.sync(&conf);
+            for host in hosts {
+                scheduler.retry_now(&host.name);
+            }
+        }
+        if refresh {
+            let conf = server.current_conf().clone();
+            server.start_refresh(&conf);
+        }
     }
 }
 
@@ -0,0 +1,38 @@
+//! Unix signals handling.
+
+use server::Server;
+
+use std::io;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+
+use signal_hook;
+use signal_hook::iterator::Signals;
+
+/// Starts a thread stopping the server on SIGINT or SIGTERM, and calling
+/// `reload` on SIGHUP.
+///
+/// A second stop signal exits right away, without waiting for the fetches.
+pub fn spawn<F>(server: Arc<Server>, reload: F) -> io::Result<()>
+where
+    F: 'static + Send + Fn(),
+{
+    let signals = Signals::new(&[
+        signal_hook::SIGINT,
+        signal_hook::SIGTERM,
+        signal_hook::SIGHUP,
+    ])?;
+    thread::spawn(move || for signal in signals.forever() {
+        if signal == signal_hook::SIGHUP {
+            reload();
+            continue;
+        }
+        if !server.stop() {
+            println!("Received signal {} again, exiting now.", signal);
+            process::exit(1);
+        }
+        println!("Received signal {}, stopping.", signal);
+    });
+    Ok(())
+}
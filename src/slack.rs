@@ -2,27 +2,42 @@ extern crate slack_hook;
 
 use self::slack_hook::{PayloadBuilder, Slack};
 
+use alerts::Notification;
+use notifier::{BoxedError, Notifier};
+
 pub fn send_alert(
     token: &str,
     channel: &str,
-    host: &str,
-    disk: &str,
-    mount: &str,
-    usage: usize,
+    message: &str,
 ) -> self::slack_hook::Result<()> {
     let slack = Slack::new(token)?;
 
     let p = PayloadBuilder::new()
-        .text(format!(
-            "`{}` (`{}`) on `{}` is getting critical at {}% usage.",
-            mount,
-            disk,
-            host,
-            usage
-        ))
+        .text(message)
         .channel(channel)
         .username("FarmView")
         .build()?;
 
     slack.send(&p)
 }
+
+pub struct SlackNotifier {
+    hook: String,
+    channel: String,
+}
+
+impl SlackNotifier {
+    pub fn new(hook: &str, channel: &str) -> Self {
+        SlackNotifier {
+            hook: hook.to_string(),
+            channel: channel.to_string(),
+        }
+    }
+}
+
+impl Notifier for SlackNotifier {
+    fn notify(&self, notification: &Notification) -> Result<(), BoxedError> {
+        send_alert(&self.hook, &self.channel, &notification.message())
+            .map_err(|e| e.to_string().into())
+    }
+}
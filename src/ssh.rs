@@ -0,0 +1,282 @@
+//! SSH connections to the hosts.
+
+use config::{expand_home, AuthConfig, AuthMethod, Config, HostConfig,
+             HostKeyPolicy, SshConfig};
+use data::HostState;
+use fetch::{BoxedError, FetchError};
+use tunnel::Tunnels;
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use ssh2;
+
+/// Tries a single authentication method.
+fn try_auth(
+    sess: &ssh2::Session,
+    auth: &AuthConfig,
+    method: AuthMethod,
+) -> Result<(), BoxedError> {
+    match method {
+        AuthMethod::Agent => sess.userauth_agent(&auth.login)?,
+        AuthMethod::PublicKey => {
+            let keypair =
+                auth.keypair.as_ref().ok_or("no keypair configured")?;
+            let public_key = auth.public_key.as_ref().map(|p| expand_home(p));
+            let passphrase = auth.passphrase().map_err(|e| e.to_string())?;
+            sess.userauth_pubkey_file(
+                &auth.login,
+                public_key.as_ref().map(|p| p.as_path()),
+                &expand_home(keypair),
+                passphrase.as_ref().map(String::as_str),
+            )?;
+        }
+        AuthMethod::Password => {
+            let password =
+                auth.password.as_ref().ok_or("no password configured")?;
+            sess.userauth_password(&auth.login, password)?;
+        }
+    }
+    Ok(())
+}
+
+fn authenticate(
+    sess: &ssh2::Session,
+    host: &HostConfig,
+    default: Option<&AuthConfig>,
+) -> Result<(), FetchError> {
+    // Do we have an authentication? Or do we have a default one?
+    let auth = host.auth.as_ref().or(default).ok_or_else(|| {
+        FetchError::new(HostState::AuthFailed, "no authentication configured")
+    })?;
+
+    let methods = auth.methods();
+    if methods.is_empty() {
+        return Err(FetchError::new(
+            HostState::AuthFailed,
+            "no authentication method configured",
+        ));
+    }
+
+    let mut errors = Vec::new();
+    for method in methods {
+        match try_auth(sess, auth, method) {
+            Ok(()) if sess.authenticated() => return Ok(()),
+            Ok(()) => errors.push(format!("{:?}: rejected", method)),
+            Err(e) => errors.push(format!("{:?}: {}", method, e)),
+        }
+    }
+
+    Err(FetchError::new(
+        HostState::AuthFailed,
+        format!("no authentication method succeeded ({})", errors.join(", ")),
+    ))
+}
+
+// Serializes modifications of the known_hosts file.
+static KNOWN_HOSTS_LOCK: Mutex<()> = Mutex::new(());
+
+/// Reads the known_hosts file, if it exists.
+///
+/// Lines libssh2 can't parse, like ed25519 keys, are skipped.
+fn read_known_hosts(
+    known_hosts: &mut ssh2::KnownHosts,
+    path: &Path,
+) -> io::Result<()> {
+    let mut content = String::new();
+    match fs::File::open(path) {
+        Ok(mut file) => file.read_to_string(&mut content)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        known_hosts
+            .read_str(line, ssh2::KnownHostFileKind::OpenSSH)
+            .ok();
+    }
+    Ok(())
+}
+
+/// Appends a host key to the known_hosts file.
+///
+/// The file is never rewritten, so lines we can't parse are kept.
+fn append_known_host(
+    sess: &ssh2::Session,
+    path: &Path,
+    name: &str,
+    key: &[u8],
+    format: ssh2::KnownHostKeyFormat,
+) -> Result<(), BoxedError> {
+    // A collection with only this entry, to format its line.
+    let mut entry = sess.known_hosts()?;
+    entry.add(name, key, "added by farmview", format)?;
+    let host = entry
+        .iter()
+        .next()
+        .ok_or("could not format the host key")??;
+    let mut line =
+        entry.write_string(&host, ssh2::KnownHostFileKind::OpenSSH)?;
+    if !line.ends_with('\n') {
+        line.push('\n');
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file =
+        fs::OpenOptions::new().append(true).create(true).open(path)?;
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// Checks the host key against the known_hosts file.
+fn verify_host_key(
+    sess: &ssh2::Session,
+    address: &str,
+    port: u16,
+    ssh: &SshConfig,
+) -> Result<(), FetchError> {
+    let policy = ssh.host_key_policy.unwrap_or_default();
+    if policy == HostKeyPolicy::Off {
+        return Ok(());
+    }
+
+    let mismatch = |e| FetchError::new(HostState::HostKeyMismatch, e);
+    let (key, key_type) = sess.host_key()
+        .ok_or_else(|| mismatch("server sent no host key".to_string()))?;
+
+    let path = ssh.known_hosts_path();
+    // Hold the lock while reading, so we don't see a partial file.
+    let _lock = KNOWN_HOSTS_LOCK.lock().unwrap();
+    let mut known_hosts = sess.known_hosts()
+        .map_err(|e| FetchError::ssh(HostState::HostKeyMismatch, e))?;
+    read_known_hosts(&mut known_hosts, &path)
+        .map_err(|e| FetchError::io(HostState::HostKeyMismatch, e))?;
+
+    match known_hosts.check_port(address, port, key) {
+        ssh2::CheckResult::Match => Ok(()),
+        ssh2::CheckResult::Mismatch => Err(mismatch(format!(
+            "host key for {} does not match {:?}",
+            address,
+            path
+        ))),
+        ssh2::CheckResult::Failure => Err(mismatch(format!(
+            "could not check host key for {}",
+            address
+        ))),
+        ssh2::CheckResult::NotFound => {
+            if policy == HostKeyPolicy::Strict {
+                return Err(mismatch(format!(
+                    "host key for {} not found in {:?}",
+                    address,
+                    path
+                )));
+            }
+
+            // Trust on first use.
+            println!("Adding host key for {} to {:?}", address, path);
+            let name = if port == 22 {
+                address.to_string()
+            } else {
+                format!("[{}]:{}", address, port)
+            };
+            let format = match key_type {
+                ssh2::HostKeyType::Rsa => ssh2::KnownHostKeyFormat::SshRsa,
+                ssh2::HostKeyType::Dss => ssh2::KnownHostKeyFormat::SshDss,
+                ssh2::HostKeyType::Unknown => {
+                    return Err(mismatch(format!(
+                        "unsupported host key type for {}",
+                        address
+                    )))
+                }
+            };
+            append_known_host(sess, &path, &name, key, format).map_err(|e| {
+                mismatch(format!("could not add the host key: {}", e))
+            })
+        }
+    }
+}
+
+/// Tries each address the name resolves to.
+fn connect_tcp(
+    address: &str,
+    port: u16,
+    timeout: Duration,
+) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in (address, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::Other, "address resolved to nothing")
+    }))
+}
+
+/// Opens an authenticated session to the host.
+///
+/// Goes through the jump hosts configured for this host, if any.
+/// The address is first resolved through the OpenSSH client config.
+pub fn connect(
+    host: &HostConfig,
+    config: &Config,
+    tunnels: &Tunnels,
+) -> Result<(TcpStream, ssh2::Session), FetchError> {
+    let host = config.resolve_host(host);
+    let chain = config
+        .jump_chain(&host)
+        .map_err(|e| FetchError::new(HostState::Unreachable, e.to_string()))?;
+    connect_through(&host, &chain, config, tunnels)
+}
+
+/// Opens an authenticated session to the host, through the given hops.
+///
+/// `chain` lists the jump hosts, the closest to us first. They, and the
+/// host, should already be resolved.
+pub fn connect_through(
+    host: &HostConfig,
+    chain: &[HostConfig],
+    config: &Config,
+    tunnels: &Tunnels,
+) -> Result<(TcpStream, ssh2::Session), FetchError> {
+    let unreachable = |e| FetchError::io(HostState::Unreachable, e);
+    let default_ssh = SshConfig::default();
+    let ssh = config.ssh.as_ref().unwrap_or(&default_ssh);
+    let settings = host.settings(Some(ssh));
+    let port = settings.port;
+
+    let tcp = if chain.is_empty() {
+        connect_tcp(&host.address, port, settings.connect_timeout)
+            .map_err(unreachable)?
+    } else {
+        tunnels.open(chain, &host.address, port, config)?
+    };
+    tcp.set_read_timeout(Some(settings.read_timeout))
+        .map_err(unreachable)?;
+    tcp.set_write_timeout(Some(settings.read_timeout))
+        .map_err(unreachable)?;
+
+    // An error here means something very wrong is going on.
+    let mut sess = ssh2::Session::new().ok_or_else(|| {
+        FetchError::new(HostState::Unreachable, "Could not create ssh session")
+    })?;
+    let timeout = settings.read_timeout;
+    sess.set_timeout(
+        (timeout.as_secs() * 1000) as u32 + timeout.subsec_nanos() / 1_000_000,
+    );
+    sess.handshake(&tcp)
+        .map_err(|e| FetchError::ssh(HostState::Unreachable, e))?;
+    verify_host_key(&sess, &host.address, port, ssh)?;
+    authenticate(&sess, host, config.default.as_ref())?;
+
+    Ok((tcp, sess))
+}
@@ -0,0 +1,181 @@
+//! A subset of OpenSSH's `ssh_config` format.
+//!
+//! Only `Host` blocks, and the `HostName`, `User`, `Port`, `IdentityFile`
+//! and `ProxyJump` options are supported. `Match` blocks are ignored.
+
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Options for a host, as found in the file.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct SshHostConfig {
+    pub hostname: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_files: Vec<String>,
+    pub proxy_jump: Option<String>,
+}
+
+#[derive(Debug)]
+struct Block {
+    // Empty for the options before the first `Host` line, which apply to all.
+    patterns: Vec<String>,
+    options: Vec<(String, String)>,
+}
+
+#[derive(Debug)]
+pub struct SshConfigFile {
+    blocks: Vec<Block>,
+}
+
+/// Matches `*` and `?` wildcards.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((&b'*', rest)) => {
+            (0..text.len() + 1).any(|i| glob(rest, &text[i..]))
+        }
+        Some((&b'?', rest)) => !text.is_empty() && glob(rest, &text[1..]),
+        Some((&c, rest)) => {
+            text.first().map_or(false, |t| t.eq_ignore_ascii_case(&c)) &&
+                glob(rest, &text[1..])
+        }
+    }
+}
+
+impl Block {
+    fn matches(&self, alias: &str) -> bool {
+        if self.patterns.is_empty() {
+            return true;
+        }
+        let mut matched = false;
+        for pattern in &self.patterns {
+            if pattern.starts_with('!') {
+                // Any negated match excludes the block.
+                if glob(pattern[1..].as_bytes(), alias.as_bytes()) {
+                    return false;
+                }
+            } else if glob(pattern.as_bytes(), alias.as_bytes()) {
+                matched = true;
+            }
+        }
+        matched
+    }
+}
+
+/// Splits `Key value` or `Key=value`.
+fn split_option(line: &str) -> Option<(String, String)> {
+    let line = line.trim();
+    let end = line.find(|c: char| c.is_whitespace() || c == '=')?;
+    let key = line[..end].to_lowercase();
+    let value = line[end..]
+        .trim_left_matches(|c: char| c.is_whitespace() || c == '=')
+        .trim()
+        .trim_matches('"');
+    if value.is_empty() {
+        None
+    } else {
+        Some((key, value.to_string()))
+    }
+}
+
+impl SshConfigFile {
+    pub fn parse(content: &str) -> Self {
+        let mut blocks = vec![
+            Block {
+                patterns: Vec::new(),
+                options: Vec::new(),
+            },
+        ];
+        // Inside a `Match` block, which we don't support.
+        let mut skipping = false;
+
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match split_option(line) {
+                Some(option) => option,
+                None => continue,
+            };
+
+            match &*key {
+                "host" => {
+                    skipping = false;
+                    blocks.push(Block {
+                        patterns: value
+                            .split_whitespace()
+                            .map(String::from)
+                            .collect(),
+                        options: Vec::new(),
+                    });
+                }
+                "match" => skipping = true,
+                _ if skipping => (),
+                _ => blocks.last_mut().unwrap().options.push((key, value)),
+            }
+        }
+
+        SshConfigFile { blocks: blocks }
+    }
+
+    /// Returns the options for this alias.
+    ///
+    /// Like OpenSSH, the first value found for an option wins.
+    pub fn resolve(&self, alias: &str) -> SshHostConfig {
+        let mut result = SshHostConfig::default();
+        for block in self.blocks.iter().filter(|b| b.matches(alias)) {
+            for &(ref key, ref value) in &block.options {
+                match &**key {
+                    "hostname" if result.hostname.is_none() => {
+                        result.hostname = Some(value.replace("%h", alias));
+                    }
+                    "user" if result.user.is_none() => {
+                        result.user = Some(value.clone());
+                    }
+                    "port" if result.port.is_none() => {
+                        result.port = value.parse().ok();
+                    }
+                    "identityfile" => {
+                        result.identity_files.push(value.clone());
+                    }
+                    "proxyjump" if result.proxy_jump.is_none() => {
+                        result.proxy_jump = Some(value.clone());
+                    }
+                    _ => (),
+                }
+            }
+        }
+        result
+    }
+}
+
+// Last file loaded, with its modification time.
+static CACHE: Mutex<Option<(PathBuf, SystemTime, Arc<SshConfigFile>)>> =
+    Mutex::new(None);
+
+/// Loads the file, re-using the last parsed version if it didn't change.
+///
+/// Returns `None` if the file cannot be read.
+pub fn load(path: &Path) -> Option<Arc<SshConfigFile>> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+
+    let mut cache = CACHE.lock().unwrap();
+    if let Some((ref cached_path, cached_time, ref file)) = *cache {
+        if cached_path == path && cached_time == modified {
+            return Some(file.clone());
+        }
+    }
+
+    let mut content = String::new();
+    fs::File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut content))
+        .ok()?;
+    let file = Arc::new(SshConfigFile::parse(&content));
+    *cache = Some((path.to_path_buf(), modified, file.clone()));
+    Some(file)
+}
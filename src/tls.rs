@@ -0,0 +1,167 @@
+//! HTTPS for the web interface and outgoing requests.
+
+use config::{expand_home, TlsConfig};
+use errors::*;
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use error_chain::ChainedError;
+use hyper;
+use hyper::net::{HttpStream, NetworkStream, SslClient, SslServer};
+use openssl::ssl::{
+    SslAcceptor, SslConnector, SslFiletype, SslMethod, SslStream,
+};
+
+/// Seconds a client gets for each step of the handshake.
+///
+/// Without it, clients that never finish could keep every thread busy.
+const HANDSHAKE_TIMEOUT: u64 = 10;
+
+/// Accepts TLS connections with the configured certificate.
+///
+/// Clones share the certificate, so a reload applies to all of them.
+#[derive(Clone)]
+pub struct Tls {
+    config: TlsConfig,
+    acceptor: Arc<RwLock<SslAcceptor>>,
+}
+
+/// Reads the certificate and private key.
+fn load(config: &TlsConfig) -> Result<SslAcceptor> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())
+        .chain_err(|| "could not set up TLS")?;
+    builder
+        .set_certificate_chain_file(expand_home(&config.certificate))
+        .chain_err(|| {
+            format!("could not load certificate {}", config.certificate)
+        })?;
+    builder
+        .set_private_key_file(
+            expand_home(&config.private_key),
+            SslFiletype::PEM,
+        )
+        .chain_err(|| {
+            format!("could not load private key {}", config.private_key)
+        })?;
+    builder
+        .check_private_key()
+        .chain_err(|| "the private key does not match the certificate")?;
+    Ok(builder.build())
+}
+
+impl Tls {
+    pub fn new(config: TlsConfig) -> Result<Self> {
+        let acceptor = load(&config)?;
+        Ok(Tls {
+            config: config,
+            acceptor: Arc::new(RwLock::new(acceptor)),
+        })
+    }
+
+    /// Reads the certificate again, for the next connections.
+    ///
+    /// If that fails, the current one is kept.
+    pub fn reload(&self) {
+        match load(&self.config) {
+            Ok(acceptor) => {
+                *self.acceptor.write().unwrap() = acceptor;
+                println!("Reloaded the TLS certificate.");
+            }
+            Err(e) => print!(
+                "Could not reload the TLS certificate, keeping the current \
+                 one.\n{}",
+                e.display_chain()
+            ),
+        }
+    }
+}
+
+impl SslServer for Tls {
+    type Stream = TlsStream;
+
+    fn wrap_server(&self, stream: HttpStream) -> hyper::Result<TlsStream> {
+        // hyper sets its own read timeouts once the handshake is done. The
+        // write timeout stays: stuck clients can't hold a thread either.
+        let timeout = Some(Duration::from_secs(HANDSHAKE_TIMEOUT));
+        stream.0.set_read_timeout(timeout)?;
+        stream.0.set_write_timeout(timeout)?;
+        let acceptor = self.acceptor.read().unwrap().clone();
+        match acceptor.accept(stream) {
+            Ok(stream) => Ok(TlsStream(Arc::new(Mutex::new(stream)))),
+            Err(e) => Err(hyper::Error::Ssl(e.to_string().into())),
+        }
+    }
+}
+
+/// Opens TLS connections, checking the certificate of the server.
+pub struct TlsClient {
+    connector: SslConnector,
+}
+
+impl TlsClient {
+    pub fn new() -> Result<Self> {
+        let builder = SslConnector::builder(SslMethod::tls())
+            .chain_err(|| "could not set up TLS")?;
+        Ok(TlsClient {
+            connector: builder.build(),
+        })
+    }
+}
+
+impl SslClient for TlsClient {
+    type Stream = TlsStream;
+
+    fn wrap_client(
+        &self,
+        stream: HttpStream,
+        host: &str,
+    ) -> hyper::Result<TlsStream> {
+        match self.connector.connect(host, stream) {
+            Ok(stream) => Ok(TlsStream(Arc::new(Mutex::new(stream)))),
+            Err(e) => Err(hyper::Error::Ssl(e.to_string().into())),
+        }
+    }
+}
+
+/// A TLS connection, which hyper wants to clone to read and write.
+///
+/// It never does both at once, so they can share the stream.
+#[derive(Clone)]
+pub struct TlsStream(Arc<Mutex<SslStream<HttpStream>>>);
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+impl NetworkStream for TlsStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.0.lock().unwrap().get_mut().peer_addr()
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.lock().unwrap().get_ref().set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.lock().unwrap().get_ref().set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.0.lock().unwrap().get_mut().close(how)
+    }
+}
@@ -0,0 +1,448 @@
+//! Tunnels through jump hosts.
+//!
+//! libssh2 needs a real socket for its handshake, so each jump host gets a
+//! forwarder thread. It owns the session to the jump host, and pumps data
+//! between `direct-tcpip` channels and local socket pairs, sleeping in
+//! `poll` when there is nothing to move.
+
+use config::{Config, HostConfig};
+use data::HostState;
+use fetch::FetchError;
+use ssh::connect_through;
+
+use std::collections::HashMap;
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+use ssh2;
+
+/// Asks a forwarder for a connection to `address:port`.
+struct OpenRequest {
+    address: String,
+    port: u16,
+    reply: mpsc::Sender<Result<TcpStream, String>>,
+}
+
+/// Seconds before trying again to reach a failed jump host.
+const RETRY_DELAY: u64 = 10;
+
+/// Seconds a forwarder stays connected without any connection through it.
+const IDLE_TIMEOUT: u64 = 120;
+
+/// Milliseconds before trying again to write to a channel.
+///
+/// A write can block on the socket or on the jump host's window, and `poll`
+/// can't tell the two apart.
+const CHANNEL_RETRY: i32 = 20;
+
+/// Most data buffered in each direction of a link, in bytes.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+enum Forwarder {
+    // Requests are sent along with a byte on the socket, to wake it up.
+    Running(mpsc::Sender<OpenRequest>, TcpStream),
+    // We couldn't reach the jump host, at this time.
+    Failed(String, Instant),
+}
+
+type Slot = Arc<Mutex<Option<Forwarder>>>;
+
+/// Forwarders to the jump hosts, shared by all hosts.
+///
+/// Forwarders stop once no connection went through them for `IDLE_TIMEOUT`
+/// seconds, or when the jump host stops answering.
+pub struct Tunnels {
+    // Indexed by the chain of jump host names.
+    forwarders: Mutex<HashMap<String, Slot>>,
+}
+
+impl Tunnels {
+    pub fn new() -> Self {
+        Tunnels {
+            forwarders: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Opens a connection to `address:port`, through the chain of hops.
+    pub fn open(
+        &self,
+        chain: &[HostConfig],
+        address: &str,
+        port: u16,
+        config: &Config,
+    ) -> Result<TcpStream, FetchError> {
+        let (last, rest) = chain.split_last().expect("empty jump chain");
+        let key = chain
+            .iter()
+            .map(|hop| &*hop.name)
+            .collect::<Vec<_>>()
+            .join(",");
+        let failed = |msg: String| {
+            FetchError::new(
+                HostState::Unreachable,
+                format!("jump host {}: {}", last.name, msg),
+            )
+        };
+
+        let slot = self.forwarders
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert_with(|| Arc::new(Mutex::new(None)))
+            .clone();
+
+        let (reply, response) = mpsc::channel();
+        {
+            // Only one thread connects to each jump host,
+            // the others wait for it and reuse the session.
+            // Requests are sent with the lock held: a forwarder takes it
+            // before stopping, so it can't miss one.
+            let mut current = slot.lock().unwrap();
+            let expired = match *current {
+                Some(Forwarder::Failed(_, when)) => {
+                    when.elapsed() > Duration::from_secs(RETRY_DELAY)
+                }
+                Some(Forwarder::Running(..)) => false,
+                None => true,
+            };
+            if expired {
+                let started = connect_through(last, rest, config, self)
+                    .map_err(|e| e.to_string())
+                    .and_then(|(tcp, sess)| {
+                        spawn_forwarder(&last.name, tcp, sess, &slot)
+                    });
+                *current = Some(match started {
+                    Ok((sender, waker)) => Forwarder::Running(sender, waker),
+                    Err(e) => Forwarder::Failed(e, Instant::now()),
+                });
+            }
+            let sent = match *current {
+                Some(Forwarder::Running(ref sender, ref waker)) => {
+                    let sent = sender.send(OpenRequest {
+                        address: address.to_string(),
+                        port: port,
+                        reply: reply,
+                    });
+                    // If the socket is full, the forwarder is awake anyway.
+                    (&*waker).write(&[0]).ok();
+                    sent.is_ok()
+                }
+                Some(Forwarder::Failed(ref e, _)) => {
+                    return Err(failed(e.clone()))
+                }
+                None => unreachable!(),
+            };
+            if !sent {
+                // The forwarder died, reconnect next time.
+                *current = None;
+                return Err(failed("connection lost".to_string()));
+            }
+        }
+
+        match response.recv() {
+            Ok(result) => result.map_err(failed),
+            Err(_) => Err(failed("connection lost".to_string())),
+        }
+    }
+}
+
+/// A channel, and the local socket it's forwarded to.
+struct Link<'sess> {
+    channel: ssh2::Channel<'sess>,
+    stream: TcpStream,
+    // Data waiting to be written to the channel
+    to_channel: Vec<u8>,
+    // Data waiting to be written to the stream
+    to_stream: Vec<u8>,
+    // The jump host closed the channel
+    remote_eof: bool,
+    closed: bool,
+}
+
+/// Returns a pair of connected local sockets.
+fn socket_pair() -> io::Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let client = TcpStream::connect(listener.local_addr()?)?;
+    let (server, _) = listener.accept()?;
+    Ok((client, server))
+}
+
+fn would_block(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock
+}
+
+/// Writes as much as possible without blocking.
+///
+/// Returns `true` if some data was written.
+fn flush_some<W: Write>(
+    writer: &mut W,
+    buffer: &mut Vec<u8>,
+) -> io::Result<bool> {
+    let mut written = 0;
+    while written < buffer.len() {
+        match writer.write(&buffer[written..]) {
+            Ok(0) => break,
+            Ok(n) => written += n,
+            Err(ref e) if would_block(e) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    buffer.drain(..written);
+    Ok(written > 0)
+}
+
+/// Reads what's available without blocking.
+///
+/// Returns `None` on EOF, and the number of bytes read otherwise.
+fn read_some<R: Read>(
+    reader: &mut R,
+    buffer: &mut Vec<u8>,
+) -> io::Result<Option<usize>> {
+    let mut chunk = [0u8; 16 * 1024];
+    match reader.read(&mut chunk) {
+        Ok(0) => Ok(None),
+        Ok(n) => {
+            buffer.extend_from_slice(&chunk[..n]);
+            Ok(Some(n))
+        }
+        Err(ref e) if would_block(e) => Ok(Some(0)),
+        Err(e) => Err(e),
+    }
+}
+
+impl<'sess> Link<'sess> {
+    /// Moves data in both directions.
+    ///
+    /// Returns `true` if anything happened.
+    fn pump(&mut self) -> io::Result<bool> {
+        let mut busy = false;
+
+        // Don't buffer too much if the other side is slow.
+        if self.to_channel.len() < BUFFER_SIZE {
+            match read_some(&mut self.stream, &mut self.to_channel)? {
+                None => self.closed = true,
+                Some(n) => busy |= n > 0,
+            }
+        }
+        busy |= flush_some(&mut self.channel, &mut self.to_channel)?;
+
+        if self.reads_channel() {
+            match read_some(&mut self.channel, &mut self.to_stream)? {
+                None => self.remote_eof = true,
+                Some(n) => busy |= n > 0,
+            }
+        }
+        busy |= flush_some(&mut self.stream, &mut self.to_stream)?;
+
+        // Close once everything the jump host sent was delivered.
+        if self.remote_eof && self.to_stream.is_empty() {
+            self.closed = true;
+        }
+
+        Ok(busy)
+    }
+
+    /// Whether there's room for what the jump host sends.
+    fn reads_channel(&self) -> bool {
+        !self.remote_eof && self.to_stream.len() < BUFFER_SIZE
+    }
+
+    /// What to wait for on the local socket.
+    fn poll_fd(&self) -> libc::pollfd {
+        let mut events = 0;
+        if self.to_channel.len() < BUFFER_SIZE {
+            events |= libc::POLLIN;
+        }
+        if !self.to_stream.is_empty() {
+            events |= libc::POLLOUT;
+        }
+        libc::pollfd {
+            // Negative descriptors are ignored, even for errors.
+            fd: if events == 0 { -1 } else { self.stream.as_raw_fd() },
+            events: events,
+            revents: 0,
+        }
+    }
+}
+
+fn open_link<'sess>(
+    sess: &'sess ssh2::Session,
+    request: &OpenRequest,
+) -> Result<(Link<'sess>, TcpStream), String> {
+    sess.set_blocking(true);
+    let channel =
+        sess.channel_direct_tcpip(&request.address, request.port, None);
+    sess.set_blocking(false);
+    let channel = channel.map_err(|e| e.to_string())?;
+
+    let (client, server) = socket_pair().map_err(|e| e.to_string())?;
+    server.set_nonblocking(true).map_err(|e| e.to_string())?;
+
+    let link = Link {
+        channel: channel,
+        stream: server,
+        to_channel: Vec::new(),
+        to_stream: Vec::new(),
+        remote_eof: false,
+        closed: false,
+    };
+    Ok((link, client))
+}
+
+fn accept<'sess>(
+    sess: &'sess ssh2::Session,
+    request: OpenRequest,
+    links: &mut Vec<Link<'sess>>,
+) {
+    let result = open_link(sess, &request).map(|(link, client)| {
+        links.push(link);
+        client
+    });
+    request.reply.send(result).ok();
+}
+
+/// Waits until a socket is ready, or `timeout` milliseconds.
+fn wait(fds: &mut [libc::pollfd], timeout: i32) -> io::Result<()> {
+    let n = unsafe {
+        libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout)
+    };
+    if n < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+fn forward(
+    name: &str,
+    tcp: &TcpStream,
+    sess: ssh2::Session,
+    requests: mpsc::Receiver<OpenRequest>,
+    mut waker: TcpStream,
+    slot: &Slot,
+) {
+    sess.set_blocking(false);
+    let mut links: Vec<Link> = Vec::new();
+    let mut last_used = Instant::now();
+
+    let error = loop {
+        // Move data until nothing happens anymore: libssh2 may have read
+        // more than what was asked, and `poll` wouldn't tell.
+        let mut busy = true;
+        while busy {
+            busy = false;
+            for request in requests.try_iter() {
+                busy = true;
+                accept(&sess, request, &mut links);
+            }
+            for link in &mut links {
+                match link.pump() {
+                    Ok(b) => busy |= b,
+                    Err(e) => {
+                        println!("Error forwarding through {}: {}", name, e);
+                        link.closed = true;
+                    }
+                }
+            }
+            links.retain(|link| !link.closed);
+        }
+
+        let keepalive = match sess.keepalive_send() {
+            Ok(seconds) => seconds,
+            Err(e) => break e.to_string(),
+        };
+
+        let mut timeout = cmp::max(keepalive, 1) as i32 * 1000;
+        if !links.is_empty() {
+            last_used = Instant::now();
+        } else {
+            let idle = Duration::from_secs(IDLE_TIMEOUT);
+            let elapsed = last_used.elapsed();
+            if elapsed >= idle {
+                let mut current = slot.lock().unwrap();
+                // Requests are sent with the lock held.
+                match requests.try_recv() {
+                    Ok(request) => accept(&sess, request, &mut links),
+                    Err(_) => {
+                        *current = None;
+                        return;
+                    }
+                }
+                continue;
+            }
+            let left = idle - elapsed;
+            let left = left.as_secs() as i32 * 1000 +
+                left.subsec_nanos() as i32 / 1_000_000 + 1;
+            timeout = cmp::min(timeout, left);
+        }
+        if links.iter().any(|link| !link.to_channel.is_empty()) {
+            timeout = cmp::min(timeout, CHANNEL_RETRY);
+        }
+
+        // The session is only read through the channels: if none wants
+        // data, whatever arrives would wake us up again and again.
+        let reading = links.iter().any(Link::reads_channel);
+        let mut fds = vec![
+            libc::pollfd {
+                fd: waker.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: if reading { tcp.as_raw_fd() } else { -1 },
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        fds.extend(links.iter().map(Link::poll_fd));
+        if let Err(e) = wait(&mut fds, timeout) {
+            break e.to_string();
+        }
+        // The requests are read above, only the wake up bytes are left.
+        let mut buffer = [0u8; 64];
+        while let Ok(n) = waker.read(&mut buffer) {
+            if n == 0 {
+                break;
+            }
+        }
+    };
+
+    println!("Lost connection to {}: {}", name, error);
+    let mut current = slot.lock().unwrap();
+    for request in requests.try_iter() {
+        request.reply.send(Err(error.clone())).ok();
+    }
+    *current = None;
+}
+
+/// Starts a forwarder thread for this jump host session.
+///
+/// Returns where to send requests, and the socket to wake it up.
+fn spawn_forwarder(
+    name: &str,
+    tcp: TcpStream,
+    sess: ssh2::Session,
+    slot: &Slot,
+) -> Result<(mpsc::Sender<OpenRequest>, TcpStream), String> {
+    let (waker, woken) = socket_pair().map_err(|e| e.to_string())?;
+    waker.set_nonblocking(true).map_err(|e| e.to_string())?;
+    woken.set_nonblocking(true).map_err(|e| e.to_string())?;
+
+    let (sender, requests) = mpsc::channel();
+    let name = name.to_string();
+    let slot = slot.clone();
+    sess.set_keepalive(true, 30);
+    thread::spawn(move || {
+        // `tcp` needs to live as long as the session.
+        forward(&name, &tcp, sess, requests, woken, &slot);
+    });
+    Ok((sender, waker))
+}
@@ -0,0 +1,478 @@
+//! Checks on the config, beyond what deserializing it catches.
+
+use auth::known_hash;
+use config::{expand_home, AuthConfig, AuthMethod, Config, HttpAuthConfig,
+             Transport};
+use ips::IpBlock;
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// Something wrong in the config.
+#[derive(Debug, Clone)]
+pub struct Problem {
+    // 1-based line in the file, when we could find it
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Finds where things are in the TOML source.
+///
+/// This is a plain text scan: it handles the usual `[[hosts]]` layout, and
+/// gives up on anything fancier.
+struct Source<'a> {
+    lines: Vec<&'a str>,
+}
+
+/// Returns the name of the `[table]` or `[[array]]` declared on this line.
+fn header(line: &str) -> Option<String> {
+    let line = line.trim();
+    if !line.starts_with('[') {
+        return None;
+    }
+    let end = line.find(']')?;
+    let name: String = line[..end]
+        .trim_left_matches('[')
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    Some(name)
+}
+
+/// Does this line set `key`?
+fn sets_key(line: &str, key: &str) -> bool {
+    let line = line.trim();
+    line.starts_with(key) && line[key.len()..].trim_left().starts_with('=')
+}
+
+impl<'a> Source<'a> {
+    fn new(source: Option<&'a str>) -> Self {
+        Source {
+            lines: source.map_or_else(Vec::new, |s| s.lines().collect()),
+        }
+    }
+
+    /// Lines of the `index`th element of `[[array]]`, with its subtables.
+    ///
+    /// Returns the 0-based line of the header, and the end of the block.
+    fn block(&self, array: &str, index: usize) -> Option<(usize, usize)> {
+        let start = self.lines
+            .iter()
+            .enumerate()
+            .filter(|&(_, line)| {
+                header(line).map_or(false, |name| name == array) &&
+                    line.trim().starts_with("[[")
+            })
+            .nth(index)?
+            .0;
+        let prefix = format!("{}.", array);
+        let end = self.lines[start + 1..]
+            .iter()
+            .position(|line| {
+                header(line).map_or(false, |name| !name.starts_with(&prefix))
+            })
+            .map_or(self.lines.len(), |i| start + 1 + i);
+        Some((start, end))
+    }
+
+    /// Line of the `index`th element of `[[array]]`.
+    fn entry(&self, array: &str, index: usize) -> Option<usize> {
+        self.block(array, index).map(|(start, _)| start + 1)
+    }
+
+    /// Line setting `key` in the `index`th element of `[[array]]`.
+    ///
+    /// Falls back to the line of the element.
+    fn key(&self, array: &str, index: usize, key: &str) -> Option<usize> {
+        let (start, end) = self.block(array, index)?;
+        let found = (start + 1..end).find(|&i| {
+            sets_key(self.lines[i], key) ||
+                header(self.lines[i])
+                    .map_or(false, |name| name == format!("{}.{}", array, key))
+        });
+        Some(found.unwrap_or(start) + 1)
+    }
+
+    /// Line setting `key` in the `[table]`, or the table itself.
+    fn table_key(&self, table: &str, key: &str) -> Option<usize> {
+        let start = self.lines
+            .iter()
+            .position(|line| header(line).map_or(false, |n| n == table))?;
+        let found = self.lines[start + 1..]
+            .iter()
+            .take_while(|line| header(line).is_none())
+            .position(|line| sets_key(line, key))
+            .map(|i| start + 1 + i);
+        Some(found.unwrap_or(start) + 1)
+    }
+}
+
+/// ` on line N`, if we know the line.
+fn on_line(line: Option<usize>) -> String {
+    line.map_or(String::new(), |line| format!(" on line {}", line))
+}
+
+/// Problems with an authentication, described as `what`.
+fn check_auth(auth: &AuthConfig, what: &str) -> Vec<(&'static str, String)> {
+    let mut result = Vec::new();
+
+    let methods = auth.methods();
+    if methods.is_empty() {
+        result.push((
+            "login",
+            format!(
+                "{} has no authentication method: set a keypair, a password \
+                 or agent = true",
+                what
+            ),
+        ));
+    }
+    if methods.contains(&AuthMethod::PublicKey) && auth.keypair.is_none() {
+        result.push((
+            "methods",
+            format!("{} uses public_key, but has no keypair", what),
+        ));
+    }
+    if methods.contains(&AuthMethod::Password) && auth.password.is_none() {
+        result.push((
+            "methods",
+            format!("{} uses password, but has no password", what),
+        ));
+    }
+
+    let files = [("keypair", &auth.keypair), ("public_key", &auth.public_key)];
+    for &(key, path) in &files {
+        if let Some(ref path) = *path {
+            if !expand_home(path).exists() {
+                result.push((
+                    key,
+                    format!("{} `{}` of {} does not exist", key, path, what),
+                ));
+            }
+        }
+    }
+    result
+}
+
+/// Problems with the users and tokens of the web interface.
+fn check_http_auth(auth: &HttpAuthConfig, source: &Source) -> Vec<Problem> {
+    let mut result = Vec::new();
+    if auth.users.is_empty() && auth.tokens.is_empty() {
+        result.push(Problem {
+            line: source.table_key("http.auth", "users"),
+            message: "http.auth has no users nor tokens, nobody can log in"
+                .to_string(),
+        });
+    }
+
+    let mut names = HashMap::new();
+    for (i, user) in auth.users.iter().enumerate() {
+        let line = source.key("http.auth.users", i, "name");
+        if let Some(first) = names.insert(&user.name, line) {
+            result.push(Problem {
+                line: line,
+                message: format!(
+                    "user `{}` is already defined{}",
+                    user.name,
+                    on_line(first)
+                ),
+            });
+        }
+        if !known_hash(&user.password_hash) {
+            result.push(Problem {
+                line: source.key("http.auth.users", i, "password_hash"),
+                message: format!(
+                    "password_hash of user `{}` is neither bcrypt nor argon2",
+                    user.name
+                ),
+            });
+        }
+    }
+    for (i, token) in auth.tokens.iter().enumerate() {
+        if token.token.is_empty() {
+            result.push(Problem {
+                line: source.key("http.auth.tokens", i, "token"),
+                message: format!("token `{}` is empty", token.name),
+            });
+        }
+    }
+    result
+}
+
+/// Checks the config for mistakes.
+///
+/// `source` is the text it was read from, to report line numbers.
+pub fn validate(config: &Config, source: Option<&str>) -> Vec<Problem> {
+    let source = Source::new(source);
+    let mut result = Vec::new();
+
+    if let Some(auth) = config.http.as_ref().and_then(|h| h.auth.as_ref()) {
+        result.extend(check_http_auth(auth, &source));
+    }
+    if let Some(tls) = config.http.as_ref().and_then(|h| h.tls.as_ref()) {
+        let files = [
+            ("certificate", &tls.certificate),
+            ("private_key", &tls.private_key),
+        ];
+        for &(key, path) in &files {
+            if !expand_home(path).exists() {
+                result.push(Problem {
+                    line: source.table_key("http.tls", key),
+                    message: format!("TLS {} `{}` does not exist", key, path),
+                });
+            }
+        }
+    }
+
+    let mut locations = HashMap::new();
+    for (i, location) in config.locations.iter().enumerate() {
+        let line = source.key("locations", i, "name");
+        if let Some(first) = locations.insert(&location.name, line) {
+            result.push(Problem {
+                line: line,
+                message: format!(
+                    "location `{}` is already defined{}",
+                    location.name,
+                    on_line(first)
+                ),
+            });
+        }
+        if let Err(e) = location.ips.parse::<IpBlock>() {
+            result.push(Problem {
+                line: source.key("locations", i, "ips"),
+                message: format!("location `{}`: {}", location.name, e),
+            });
+        }
+    }
+
+    if let Some(ref auth) = config.default {
+        for (key, message) in check_auth(auth, "the default authentication") {
+            result.push(Problem {
+                line: source.table_key("default", key),
+                message: message,
+            });
+        }
+    }
+
+    let mut names = HashMap::new();
+    let mut addresses = HashMap::new();
+    for (i, host) in config.hosts.iter().enumerate() {
+        let line = source.entry("hosts", i);
+        let what = format!("host `{}`", host.name);
+
+        if let Some(first) = names.insert(&host.name, line) {
+            result.push(Problem {
+                line: source.key("hosts", i, "name"),
+                message: format!(
+                    "host name `{}` is already used{}",
+                    host.name,
+                    on_line(first)
+                ),
+            });
+        }
+
+        let transport = host.transport.unwrap_or_default();
+        if transport == Transport::Ssh {
+            let address = (&host.address, host.port);
+            if let Some(&(ref other, first)) = addresses.get(&address) {
+                result.push(Problem {
+                    line: source.key("hosts", i, "address"),
+                    message: format!(
+                        "{} has the same address as host `{}`{}",
+                        what,
+                        other,
+                        on_line(first)
+                    ),
+                });
+            } else {
+                addresses.insert(address, (host.name.clone(), line));
+            }
+        }
+        // `~/.ssh/config` may still give a user and a key.
+        let no_auth = host.auth.is_none() && config.default.is_none() &&
+            config.resolve_host(host).auth.is_none();
+        if transport == Transport::Ssh && no_auth {
+            result.push(Problem {
+                line: line,
+                message: format!(
+                    "{} has no auth, and there is no [default] authentication",
+                    what
+                ),
+            });
+        }
+        if transport == Transport::Push && host.token.is_none() {
+            result.push(Problem {
+                line: line,
+                message: format!("{} is pushing, but has no token", what),
+            });
+        }
+
+        if let Some(ref location) = host.location {
+            if !locations.contains_key(location) {
+                result.push(Problem {
+                    line: source.key("hosts", i, "location"),
+                    message: format!(
+                        "{} is in the unknown location `{}`",
+                        what,
+                        location
+                    ),
+                });
+            }
+        }
+
+        if let Some(ref auth) = host.auth {
+            for (key, message) in check_auth(auth, &what) {
+                result.push(Problem {
+                    line: source.key("hosts", i, key),
+                    message: message,
+                });
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use toml;
+
+    /// Doesn't read `~/.ssh/config`, so the tests don't depend on it.
+    const SSH: &str = "
+[ssh]
+    config_file = \"\"
+";
+
+    fn problems(source: &str) -> Vec<(Option<usize>, String)> {
+        let source = format!("{}{}", source, SSH);
+        let config: Config = toml::from_str(&source).unwrap();
+        validate(&config, Some(&source))
+            .into_iter()
+            .map(|problem| (problem.line, problem.message))
+            .collect()
+    }
+
+    #[test]
+    fn reports_line_numbers() {
+        let source = "\
+[[locations]]
+    name = \"room\"
+    ips = \"10.0.0.0/33\"
+
+[default]
+    login = \"farmview\"
+    password = \"secret\"
+
+[[hosts]]
+    name = \"node1\"
+    address = \"node1.lan\"
+    iface = \"eth0\"
+
+[[hosts]]
+    name = \"node2\"
+    address = \"node2.lan\"
+    iface = \"eth0\"
+    location = \"attic\"
+";
+        let found = problems(source);
+        assert_eq!(found.len(), 2, "{:?}", found);
+        assert_eq!(found[0].0, Some(3));
+        assert!(found[0].1.starts_with("location `room`: "));
+        assert_eq!(
+            found[1],
+            (
+                Some(18),
+                "host `node2` is in the unknown location `attic`".to_string(),
+            )
+        );
+    }
+
+    #[test]
+    fn finds_duplicates() {
+        let source = "\
+locations = []
+
+[default]
+    login = \"farmview\"
+    password = \"secret\"
+
+[[hosts]]
+    name = \"node1\"
+    address = \"node1.lan\"
+    iface = \"eth0\"
+
+[[hosts]]
+    name = \"node1\"
+    address = \"node2.lan\"
+    iface = \"eth0\"
+
+[[hosts]]
+    name = \"node3\"
+    address = \"node1.lan\"
+    iface = \"eth0\"
+";
+        assert_eq!(
+            problems(source),
+            vec![
+                (
+                    Some(13),
+                    "host name `node1` is already used on line 7".to_string(),
+                ),
+                (
+                    Some(19),
+                    "host `node3` has the same address as host `node1` on \
+                     line 7"
+                        .to_string(),
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn needs_some_auth() {
+        let source = "\
+locations = []
+
+[[hosts]]
+    name = \"node1\"
+    address = \"node1.lan\"
+    iface = \"eth0\"
+
+[[hosts]]
+    name = \"node2\"
+    address = \"node2.lan\"
+    iface = \"eth0\"
+    [hosts.auth]
+        login = \"farmview\"
+        agent = true
+";
+        assert_eq!(
+            problems(source),
+            vec![
+                (
+                    Some(3),
+                    "host `node1` has no auth, and there is no [default] \
+                     authentication"
+                        .to_string(),
+                ),
+            ]
+        );
+
+        let source = format!(
+            "{}\n[default]\n    login = \"farmview\"\n    agent = true\n",
+            source
+        );
+        assert_eq!(problems(&source), vec![]);
+    }
+}
@@ -0,0 +1,38 @@
+//! Reloads the config file when it changes on disk.
+
+use server::Server;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// How often the config file is checked.
+const POLL_INTERVAL: u64 = 2;
+
+/// What tells us the file changed: its modification time and size.
+fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Starts a thread reloading the config file whenever it changes, until the
+/// server stops.
+pub fn spawn(server: Arc<Server>, path: PathBuf) {
+    thread::spawn(move || {
+        let mut last = stamp(&path);
+        while !server.is_stopping() {
+            thread::sleep(Duration::from_secs(POLL_INTERVAL));
+            let current = stamp(&path);
+            if current == last {
+                continue;
+            }
+            last = current;
+            // Deleted, or being replaced: wait for the new file.
+            if current.is_some() {
+                server.reload(&path);
+            }
+        }
+    });
+}